@@ -1,58 +1,350 @@
-use bevy::prelude::*;
+use bevy::{ecs::system::EntityCommands, prelude::*};
 use bevy_rapier2d::prelude::*;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::Player;
+use crate::{
+    health::{self, DeathEvent, EntityKind},
+    input::PlayerInputs,
+    level::{self, CurrentLevel, Level, LevelBuiltEvent},
+    player::Dead,
+    state::AppState,
+    tick::{GameRng, GameTime, TickApp},
+    Player,
+};
 
 pub struct CartridgePlugin;
 
 impl Plugin for CartridgePlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(spawn_cart_pickup)
-            .add_system(check_pickup);
+        app.add_tick_system_set(
+            SystemSet::on_enter(AppState::Playing).with_system(setup_pickup_spawner),
+        )
+        .add_tick_system_set(
+            SystemSet::on_update(AppState::Playing)
+                .with_system(spawn_first_pickup.after(level::build_level))
+                .with_system(check_pickup)
+                .with_system(swap_cartridge)
+                .with_system(spawn_pickups_over_time)
+                .with_system(drop_cartridges.after(health::death)),
+        );
     }
 }
 
-#[derive(Component)]
-struct CartridgePickup;
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CartridgeKind {
+    // double damage
+    DamageBoost,
+    // bullets go through a few enemies
+    Piercing,
+    // bullets blow up and hurt everything nearby
+    Explosive,
+    // bullets bounce off walls
+    Ricochet,
+    // shoot twice as often
+    FireRate,
+}
+
+impl CartridgeKind {
+    const ALL: [CartridgeKind; 5] = [
+        CartridgeKind::DamageBoost,
+        CartridgeKind::Piercing,
+        CartridgeKind::Explosive,
+        CartridgeKind::Ricochet,
+        CartridgeKind::FireRate,
+    ];
+
+    // how many shots a fresh cartridge lasts
+    fn power(&self) -> u32 {
+        match self {
+            CartridgeKind::DamageBoost => 30,
+            CartridgeKind::Piercing => 25,
+            CartridgeKind::Explosive => 12,
+            CartridgeKind::Ricochet => 30,
+            CartridgeKind::FireRate => 40,
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            CartridgeKind::DamageBoost => Color::rgb(0.2, 0.4, 0.8),
+            CartridgeKind::Piercing => Color::rgb(0.8, 0.8, 0.2),
+            CartridgeKind::Explosive => Color::rgb(0.9, 0.4, 0.1),
+            CartridgeKind::Ricochet => Color::rgb(0.2, 0.8, 0.8),
+            CartridgeKind::FireRate => Color::rgb(0.8, 0.2, 0.8),
+        }
+    }
+}
+
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct CartridgePickup {
+    pub kind: CartridgeKind,
+}
 
-#[derive(Component)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct Cartridge {
-    power: usize,
+    pub kind: CartridgeKind,
+    // shots left before it ejects
+    pub power: u32,
+}
+
+impl Cartridge {
+    pub fn new(kind: CartridgeKind) -> Self {
+        Cartridge {
+            kind,
+            power: kind.power(),
+        }
+    }
+
+    pub fn damage(&self, damage: u32) -> u32 {
+        match self.kind {
+            CartridgeKind::DamageBoost => damage * 2,
+            _ => damage,
+        }
+    }
+
+    // Gun::shoot compares this against time_between_shots
+    pub fn time_since_last_shot(&self, seconds: f32) -> f32 {
+        match self.kind {
+            CartridgeKind::FireRate => seconds * 2.,
+            _ => seconds,
+        }
+    }
+
+    // the components bullet_collision_rapier looks for
+    pub fn insert_bullet_effect(&self, bullet: &mut EntityCommands) {
+        match self.kind {
+            CartridgeKind::Piercing => {
+                bullet.insert(Piercing { hits_left: 3 });
+            }
+            CartridgeKind::Explosive => {
+                bullet.insert(Explosive {
+                    radius: 120.,
+                    damage: 2,
+                });
+            }
+            CartridgeKind::Ricochet => {
+                bullet.insert(Ricochet { bounces_left: 2 });
+            }
+            CartridgeKind::DamageBoost | CartridgeKind::FireRate => (),
+        }
+    }
+}
+
+// the first cartridge is the loaded one, the rest are spares
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct CartridgeInventory {
+    pub cartridges: Vec<Cartridge>,
+    pub capacity: usize,
+}
+
+impl CartridgeInventory {
+    pub fn new(capacity: usize) -> Self {
+        CartridgeInventory {
+            cartridges: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn loaded(&self) -> Option<&Cartridge> {
+        self.cartridges.first()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.cartridges.len() >= self.capacity
+    }
+
+    // called once per shot
+    pub fn use_power(&mut self) {
+        let empty = match self.cartridges.first_mut() {
+            Some(cart) => {
+                cart.power = cart.power.saturating_sub(1);
+                cart.power == 0
+            }
+            None => false,
+        };
+
+        if empty {
+            let cart = self.cartridges.remove(0);
+            println!("{:?} cartridge ejected", cart.kind);
+        }
+    }
+
+    // put the next spare in and the loaded one at the back
+    pub fn swap(&mut self) {
+        if self.cartridges.len() > 1 {
+            self.cartridges.rotate_left(1);
+        }
+    }
 }
 
-fn spawn_cart_pickup(mut commands: Commands) {
+// goes through enemies
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct Piercing {
+    pub hits_left: u32,
+}
+
+// hurts every enemy in the radius when the bullet stops
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct Explosive {
+    pub radius: f32,
+    pub damage: u32,
+}
+
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct Ricochet {
+    pub bounces_left: u32,
+}
+
+// spawns a new pickup every so often
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PickupSpawner {
+    #[serde(with = "crate::snapshot::timer")]
+    timer: Timer,
+}
+
+// don't let them pile up if the player ignores them
+const MAX_PICKUPS: usize = 5;
+
+// enemies drop one this often
+const DROP_CHANCE: f64 = 0.1;
+
+// once the level is there, so it doesn't land inside a wall
+fn spawn_first_pickup(
+    mut commands: Commands,
+    mut ev_built: EventReader<LevelBuiltEvent>,
+    levels: Res<Assets<Level>>,
+) {
+    for ev in ev_built.iter() {
+        let position = levels
+            .get(&ev.level)
+            .and_then(|level| level.pickup_spawns.first().copied())
+            .unwrap_or(Vec2::new(300., 150.));
+        spawn_cart_pickup(&mut commands, position, CartridgeKind::DamageBoost);
+    }
+}
+
+fn setup_pickup_spawner(mut commands: Commands) {
+    commands.insert_resource(PickupSpawner {
+        timer: Timer::from_seconds(20.0, true),
+    });
+}
+
+pub fn spawn_cart_pickup(commands: &mut Commands, position: Vec2, kind: CartridgeKind) {
     commands
         .spawn_bundle(SpriteBundle {
             sprite: Sprite {
-                color: Color::rgb(0.2, 0.4, 0.8),
+                color: kind.color(),
                 custom_size: Some(Vec2::new(15., 15.)),
                 ..default()
             },
             transform: Transform {
-                translation: Vec3::new(300., 150., 0.),
+                translation: position.extend(0.),
                 ..default()
             },
             ..default()
         })
-        .insert(CartridgePickup)
+        .insert(CartridgePickup { kind })
         .insert(RigidBody::Dynamic)
         .insert(Collider::ball(7.5))
         .insert(Sensor);
 }
 
+fn spawn_pickups_over_time(
+    mut commands: Commands,
+    mut spawner: ResMut<PickupSpawner>,
+    q_pickups: Query<(), With<CartridgePickup>>,
+    current_level: Res<CurrentLevel>,
+    levels: Res<Assets<Level>>,
+    time: Res<GameTime>,
+    mut rng: ResMut<GameRng>,
+) {
+    if !spawner.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    if q_pickups.iter().count() >= MAX_PICKUPS {
+        return;
+    }
+
+    let spawns = current_level
+        .get(&levels)
+        .map(|level| level.pickup_spawns.as_slice())
+        .unwrap_or(&[]);
+    let position = match spawns.choose(&mut *rng) {
+        Some(position) => *position,
+        // somewhere inside the old box
+        None => Vec2::new(rng.gen_range(-850.0..850.0), rng.gen_range(-450.0..450.0)),
+    };
+    let kind = *CartridgeKind::ALL.choose(&mut *rng).unwrap();
+    spawn_cart_pickup(&mut commands, position, kind);
+}
+
+fn drop_cartridges(
+    mut commands: Commands,
+    mut ev_death: EventReader<DeathEvent>,
+    q_pickups: Query<(), With<CartridgePickup>>,
+    mut rng: ResMut<GameRng>,
+) {
+    let mut pickups = q_pickups.iter().count();
+
+    for ev in ev_death.iter() {
+        if !matches!(ev.kind, EntityKind::Enemy(_)) {
+            continue;
+        }
+
+        if pickups < MAX_PICKUPS && rng.gen_bool(DROP_CHANCE) {
+            let kind = *CartridgeKind::ALL.choose(&mut *rng).unwrap();
+            spawn_cart_pickup(&mut commands, ev.position, kind);
+            pickups += 1;
+        }
+    }
+}
+
 fn check_pickup(
     mut commands: Commands,
     rapier_context: Res<RapierContext>,
-    q_cart: Query<(Entity, &Transform), With<CartridgePickup>>,
-    q_player: Query<(Entity, &Transform), With<Player>>,
+    q_cart: Query<(Entity, &CartridgePickup)>,
+    mut q_player: Query<(Entity, &Player, &mut CartridgeInventory), Without<Dead>>,
+) {
+    // two players on the same pickup, the first one gets it
+    let mut taken = Vec::new();
+
+    for (player, slot, mut inventory) in q_player.iter_mut() {
+        for (cart, pickup) in q_cart.iter() {
+            // leave it on the ground if there's no room
+            if inventory.is_full() {
+                break;
+            }
+            if taken.contains(&cart) {
+                continue;
+            }
+
+            if rapier_context.intersection_pair(cart, player) == Some(true) {
+                println!(
+                    "Player {} picked up a {:?} cartridge",
+                    slot.0 + 1,
+                    pickup.kind
+                );
+                commands.entity(cart).despawn();
+                inventory.cartridges.push(Cartridge::new(pickup.kind));
+                taken.push(cart);
+            }
+        }
+    }
+}
+
+fn swap_cartridge(
+    player_inputs: Res<PlayerInputs>,
+    mut q_inventory: Query<(&Player, &mut CartridgeInventory), Without<Dead>>,
 ) {
-    let player = q_player.single();
+    for (player, mut inventory) in q_inventory.iter_mut() {
+        if !player_inputs.get(player).swap_cartridge {
+            continue;
+        }
 
-    for cart in q_cart.iter() {
-        if rapier_context.intersection_pair(cart.0, player.0) == Some(true) {
-            println!("Player picked up the cartridge");
-            commands.entity(cart.0).despawn();
-            commands.entity(player.0).insert(Cartridge { power: 100 });
+        inventory.swap();
+        if let Some(cart) = inventory.loaded() {
+            println!("Loaded {:?} cartridge ({} left)", cart.kind, cart.power);
         }
     }
 }
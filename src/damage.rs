@@ -0,0 +1,291 @@
+use bevy::prelude::*;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    health::{self, Health, Regeneration, Shield},
+    state::AppState,
+    tick::{GameRng, GameTime, TickApp},
+};
+
+// everything that hurts something sends a DamageEvent
+// apply_damage runs it through armor, resistances, crits, etc
+// and is the only thing that touches Health.
+// listen for DamageDealt to react to what actually happened
+// and health::DeathEvent for kills
+pub struct DamagePlugin;
+
+impl Plugin for DamagePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_tick_event::<DamageEvent>()
+            .add_tick_event::<DamageDealt>()
+            .add_tick_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(apply_damage.before(health::death))
+                    .with_system(invulnerability.after(apply_damage))
+                    .with_system(damage_dealt.after(apply_damage)),
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DamageKind {
+    Bullet,
+    Explosion,
+    Contact,
+}
+
+pub struct DamageEvent {
+    pub target: Entity,
+    // whoever fired the bullet or touched the target
+    pub source: Option<Entity>,
+    pub amount: u32,
+    pub kind: DamageKind,
+    pub position: Vec2,
+}
+
+// after all the modifiers
+pub struct DamageDealt {
+    pub target: Entity,
+    pub source: Option<Entity>,
+    // taken off Health
+    pub amount: u32,
+    // soaked up by a Shield
+    pub shielded: u32,
+    pub kind: DamageKind,
+    pub position: Vec2,
+    pub crit: bool,
+}
+
+// taken off every hit, but a hit always does at least 1
+#[derive(Component)]
+pub struct Armor(pub u32);
+
+// damage of a kind is multiplied by this. 0.5 takes half, 2.0 takes double
+#[derive(Component)]
+pub struct Resistances(pub Vec<(DamageKind, f32)>);
+
+impl Resistances {
+    fn multiplier(&self, kind: DamageKind) -> f32 {
+        self.0
+            .iter()
+            .filter(|(k, _)| *k == kind)
+            .map(|(_, multiplier)| multiplier)
+            .product()
+    }
+}
+
+// on the attacker
+#[derive(Component)]
+pub struct CritChance {
+    pub chance: f64,
+    pub multiplier: f32,
+}
+
+// after getting hit, become invulnerable for this long
+#[derive(Component)]
+pub struct IFrames(pub f32);
+
+// can't be hurt until this runs out
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct Invulnerable {
+    #[serde(with = "crate::snapshot::timer")]
+    timer: Timer,
+    #[serde(with = "crate::snapshot::timer")]
+    flash_timer: Timer,
+}
+
+impl Invulnerable {
+    pub fn new(seconds: f32) -> Self {
+        Invulnerable {
+            timer: Timer::from_seconds(seconds, false),
+            flash_timer: Timer::from_seconds(0.1, true),
+        }
+    }
+}
+
+pub fn apply_damage(
+    mut commands: Commands,
+    mut ev_damage: EventReader<DamageEvent>,
+    mut q_targets: Query<(
+        &mut Health,
+        Option<&Armor>,
+        Option<&Resistances>,
+        Option<&Invulnerable>,
+        Option<&IFrames>,
+        Option<&mut Shield>,
+        Option<&mut Regeneration>,
+    )>,
+    q_sources: Query<&CritChance>,
+    mut ev_dealt: EventWriter<DamageDealt>,
+    mut rng: ResMut<GameRng>,
+) {
+    // Invulnerable isn't added until the end of the frame
+    // so keep track of who got it this frame
+    let mut became_invulnerable: Vec<Entity> = Vec::new();
+
+    for ev in ev_damage.iter() {
+        let (mut hp, armor, resistances, invulnerable, iframes, shield, regen) =
+            match q_targets.get_mut(ev.target) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+
+        if hp.is_dead() || invulnerable.is_some() || became_invulnerable.contains(&ev.target) {
+            continue;
+        }
+
+        let mut amount = ev.amount as f32;
+        let mut crit = false;
+
+        if let Some(source) = ev.source {
+            if let Ok(crit_chance) = q_sources.get(source) {
+                if rng.gen_bool(crit_chance.chance) {
+                    amount *= crit_chance.multiplier;
+                    crit = true;
+                }
+            }
+        }
+
+        if let Some(resistances) = resistances {
+            amount *= resistances.multiplier(ev.kind);
+        }
+
+        let mut amount = amount.round() as u32;
+        // fully resisted
+        if amount == 0 {
+            continue;
+        }
+
+        if let Some(armor) = armor {
+            amount = amount.saturating_sub(armor.0).max(1);
+        }
+
+        if let Some(mut regen) = regen {
+            regen.interrupt();
+        }
+
+        // the shield takes it first
+        let mut shielded = 0;
+        if let Some(mut shield) = shield {
+            let through = shield.absorb(amount);
+            shielded = amount - through;
+            amount = through;
+        }
+
+        hp.take_damage(amount, ev.source);
+
+        if let Some(iframes) = iframes {
+            commands
+                .entity(ev.target)
+                .insert(Invulnerable::new(iframes.0));
+            became_invulnerable.push(ev.target);
+        }
+
+        ev_dealt.send(DamageDealt {
+            target: ev.target,
+            source: ev.source,
+            amount,
+            shielded,
+            kind: ev.kind,
+            position: ev.position,
+            crit,
+        });
+    }
+}
+
+// flash while invulnerable
+fn invulnerability(
+    mut commands: Commands,
+    mut q_invulnerable: Query<(Entity, &mut Invulnerable, &mut Sprite)>,
+    time: Res<GameTime>,
+) {
+    for (entity, mut invulnerable, mut sprite) in q_invulnerable.iter_mut() {
+        if invulnerable.flash_timer.tick(time.delta()).just_finished() {
+            let alpha = if sprite.color.a() < 1.0 { 1.0 } else { 0.3 };
+            sprite.color.set_a(alpha);
+        }
+
+        if invulnerable.timer.tick(time.delta()).just_finished() {
+            sprite.color.set_a(1.0);
+            commands.entity(entity).remove::<Invulnerable>();
+        }
+    }
+}
+
+fn damage_dealt(mut ev_dealt: EventReader<DamageDealt>) {
+    for ev in ev_dealt.iter() {
+        if ev.crit {
+            println!(
+                "Crit! {} {:?} damage to {:?} at {:?}",
+                ev.amount, ev.kind, ev.target, ev.position
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{headless::HeadlessApp, input::InputScript};
+
+    // a target out of the way of the fight, hit once
+    fn hit(target: impl Bundle, amount: u32, kind: DamageKind) -> (Health, Option<Shield>) {
+        let mut sim = HeadlessApp::new(InputScript::default());
+        sim.run_ticks(1);
+
+        let target = sim
+            .world()
+            .spawn()
+            .insert(Transform::from_xyz(5000., 5000., 0.))
+            .insert_bundle(target)
+            .id();
+        sim.world()
+            .resource_mut::<Events<DamageEvent>>()
+            .send(DamageEvent {
+                target,
+                source: None,
+                amount,
+                kind,
+                position: Vec2::ZERO,
+            });
+        sim.run_ticks(1);
+
+        let world = sim.world();
+        let hp = world.get::<Health>(target).unwrap().clone();
+        let shield = world.get::<Shield>(target).cloned();
+        (hp, shield)
+    }
+
+    #[test]
+    fn armor_takes_some_off() {
+        let (hp, _) = hit((Health::new(10), Armor(2)), 5, DamageKind::Bullet);
+        assert_eq!(hp.current(), 7);
+    }
+
+    #[test]
+    fn armor_always_lets_one_through() {
+        let (hp, _) = hit((Health::new(10), Armor(20)), 5, DamageKind::Bullet);
+        assert_eq!(hp.current(), 9);
+    }
+
+    #[test]
+    fn resistances_scale_by_kind() {
+        let resistances = || Resistances(vec![(DamageKind::Explosion, 0.5)]);
+        let (hp, _) = hit((Health::new(10), resistances()), 4, DamageKind::Explosion);
+        assert_eq!(hp.current(), 8);
+        let (hp, _) = hit((Health::new(10), resistances()), 4, DamageKind::Bullet);
+        assert_eq!(hp.current(), 6);
+    }
+
+    #[test]
+    fn shield_soaks_up_damage_first() {
+        let (hp, shield) = hit(
+            (Health::new(10), Shield::new(3, 0., 10.)),
+            5,
+            DamageKind::Contact,
+        );
+        assert_eq!(shield.unwrap().current, 0);
+        assert_eq!(hp.current(), 8);
+    }
+}
@@ -1,90 +1,334 @@
-use bevy::prelude::*;
+use bevy::{ecs::system::EntityCommands, prelude::*};
 use bevy_rapier2d::prelude::*;
-use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{health, Player};
+use crate::{
+    damage::{Armor, DamageKind, Resistances},
+    health::{self, DeathDelay, DeathEvent, Dying},
+    player::{ContactDamage, Dead},
+    shooting,
+    state::AppState,
+    tick::{GameTime, TickApp},
+    Player,
+};
 
 #[derive(Component)]
-pub struct Enemy;
+pub struct Enemy(pub EnemyKind);
 
 pub struct EnemyPlugin;
 
 impl Plugin for EnemyPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(spawn_enemies)
-            .add_event::<EnemySpawnEvent>()
-            .add_system(how_to_spawn_enemies)
-            .add_system(when_to_spawn_enemies)
-            .add_system(enemy_movement);
+        app.add_tick_event::<EnemySpawnEvent>().add_tick_system_set(
+            SystemSet::on_update(AppState::Playing)
+                .with_system(how_to_spawn_enemies)
+                .with_system(enemy_movement)
+                .with_system(charge)
+                .with_system(ranged_attack)
+                // the splitlings need to be alive before the wave director
+                // checks if everything is dead
+                .with_system(
+                    split_on_death
+                        .after(health::death)
+                        .before(how_to_spawn_enemies),
+                ),
+        );
     }
 }
 
-struct EnemySpawnEvent;
-
-fn spawn_enemies(mut ev_spawn: EventWriter<EnemySpawnEvent>) {
-    let num = 5;
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EnemyKind {
+    // walks straight at you
+    Walker,
+    // walks, stops to wind up, then dashes in a straight line
+    Charger,
+    // keeps its distance and shoots at you
+    Shooter,
+    // slow with lots of health
+    Tank,
+    // splits into splitlings when it dies
+    Splitter,
+    Splitling,
+}
 
-    // spawn 5 enemies at the start.
-    // when_to_spawn will also queue an enemy to spawn
-    // as there are 0 at startup
-    // and this event is run next frame
-    // giving when_to 1 frame at the start to see 0 enemies
-    for _ in 0..num {
-        ev_spawn.send(EnemySpawnEvent);
+impl EnemyKind {
+    // colour and size
+    pub fn look(&self) -> (Color, f32) {
+        match self {
+            EnemyKind::Walker => (Color::RED, 35.),
+            EnemyKind::Charger => (Color::ORANGE, 35.),
+            EnemyKind::Shooter => (Color::PURPLE, 30.),
+            EnemyKind::Tank => (Color::MAROON, 60.),
+            EnemyKind::Splitter => (Color::GREEN, 45.),
+            EnemyKind::Splitling => (Color::LIME_GREEN, 20.),
+        }
     }
 }
 
+// the wave director decides what and where
+pub struct EnemySpawnEvent {
+    pub kind: EnemyKind,
+    pub position: Vec2,
+}
+
+// walk straight at the player
+#[derive(Component)]
+struct Chase {
+    speed: f32,
+}
+
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct Charge {
+    walk_speed: f32,
+    dash_speed: f32,
+    // start winding up when the player is this close
+    range: f32,
+    state: ChargeState,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+enum ChargeState {
+    Approaching,
+    // stands still so the player can see it coming
+    WindingUp(#[serde(with = "crate::snapshot::timer")] Timer),
+    Dashing {
+        dir: Vec3,
+        #[serde(with = "crate::snapshot::timer")]
+        timer: Timer,
+    },
+    Recovering(#[serde(with = "crate::snapshot::timer")] Timer),
+}
+
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct RangedAttack {
+    speed: f32,
+    // stop walking when the player is this close
+    range: f32,
+    #[serde(with = "crate::snapshot::timer")]
+    fire_timer: Timer,
+}
+
+#[derive(Component)]
+struct Splitter {
+    children: u32,
+}
+
+type Targets<'w, 's> =
+    Query<'w, 's, &'static Transform, (With<Player>, Without<Enemy>, Without<Dead>)>;
+
+// enemies go after whichever living player is closest
+// None once everyone is dead
+fn nearest_player(q_player: &Targets, pos: Vec3) -> Option<Vec3> {
+    q_player
+        .iter()
+        .map(|player| player.translation)
+        .min_by(|a, b| a.distance_squared(pos).total_cmp(&b.distance_squared(pos)))
+}
+
 fn enemy_movement(
-    q_player: Query<&Transform, (With<Player>, Without<Enemy>)>,
-    mut q_enemy: Query<&mut Transform, With<Enemy>>,
-    time: Res<Time>,
+    q_player: Targets,
+    mut q_enemy: Query<(&mut Transform, &Chase), (With<Enemy>, Without<Dying>)>,
+    time: Res<GameTime>,
 ) {
-    let player_pos = q_player.get_single().unwrap().translation;
-
-    for mut enemy_trans in q_enemy.iter_mut() {
+    for (mut enemy_trans, chase) in q_enemy.iter_mut() {
+        let player_pos = match nearest_player(&q_player, enemy_trans.translation) {
+            Some(pos) => pos,
+            None => return,
+        };
         let dir = player_pos - enemy_trans.translation;
-        enemy_trans.translation += dir.normalize_or_zero() * 100. * time.delta_seconds();
+        enemy_trans.translation += dir.normalize_or_zero() * chase.speed * time.delta_seconds();
     }
 }
 
-fn when_to_spawn_enemies(mut ev_spawn: EventWriter<EnemySpawnEvent>, q_enemies: Query<&Enemy>) {
-    // check how many enemies there are
-    let num_enemies = q_enemies.iter().len();
+fn charge(
+    q_player: Targets,
+    mut q_charger: Query<(&mut Transform, &mut Charge), (With<Enemy>, Without<Dying>)>,
+    time: Res<GameTime>,
+) {
+    for (mut transform, mut charge) in q_charger.iter_mut() {
+        let player_pos = match nearest_player(&q_player, transform.translation) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let to_player = player_pos - transform.translation;
+        let charge = &mut *charge;
+
+        match &mut charge.state {
+            ChargeState::Approaching => {
+                if to_player.length() < charge.range {
+                    charge.state = ChargeState::WindingUp(Timer::from_seconds(0.5, false));
+                } else {
+                    transform.translation +=
+                        to_player.normalize_or_zero() * charge.walk_speed * time.delta_seconds();
+                }
+            }
+            ChargeState::WindingUp(timer) => {
+                if timer.tick(time.delta()).just_finished() {
+                    // lock in the direction when the dash starts
+                    // so you can sidestep it
+                    charge.state = ChargeState::Dashing {
+                        dir: to_player.normalize_or_zero(),
+                        timer: Timer::from_seconds(0.4, false),
+                    };
+                }
+            }
+            ChargeState::Dashing { dir, timer } => {
+                transform.translation += *dir * charge.dash_speed * time.delta_seconds();
+                if timer.tick(time.delta()).just_finished() {
+                    charge.state = ChargeState::Recovering(Timer::from_seconds(1.0, false));
+                }
+            }
+            ChargeState::Recovering(timer) => {
+                if timer.tick(time.delta()).just_finished() {
+                    charge.state = ChargeState::Approaching;
+                }
+            }
+        }
+    }
+}
+
+fn ranged_attack(
+    mut commands: Commands,
+    q_player: Targets,
+    mut q_shooter: Query<
+        (Entity, &mut Transform, &mut RangedAttack),
+        (With<Enemy>, Without<Dying>),
+    >,
+    time: Res<GameTime>,
+) {
+    for (shooter, mut transform, mut attack) in q_shooter.iter_mut() {
+        let player_pos = match nearest_player(&q_player, transform.translation) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let to_player = player_pos - transform.translation;
+
+        if to_player.length() > attack.range {
+            transform.translation +=
+                to_player.normalize_or_zero() * attack.speed * time.delta_seconds();
+        }
 
-    // each frame, check if there are fewer than 2 enemies.
-    // if there is, spawn one enemy
-    // enemy will be spawned next frame
-    // will always spawn 1 enemy on startup
-    // as this sees 0 enemies before they have a chance to spawn
-    if num_enemies < 2 {
-        ev_spawn.send(EnemySpawnEvent);
+        // shoots through the same bullet code as the player
+        if attack.fire_timer.tick(time.delta()).just_finished() {
+            shooting::spawn_enemy_bullet(
+                &mut commands,
+                Some(shooter),
+                transform.translation,
+                to_player.truncate().normalize_or_zero(),
+            );
+        }
     }
 }
 
-fn how_to_spawn_enemies(mut commands: Commands, mut ev_spawn: EventReader<EnemySpawnEvent>) {
+fn split_on_death(
+    mut ev_death: EventReader<DeathEvent>,
+    q_splitter: Query<&Splitter>,
+    mut ev_spawn: EventWriter<EnemySpawnEvent>,
+) {
+    for ev in ev_death.iter() {
+        if let Ok(splitter) = q_splitter.get(ev.entity) {
+            for i in 0..splitter.children {
+                // spread them out in a circle so they don't start stacked
+                let angle = i as f32 * std::f32::consts::TAU / splitter.children as f32;
+                let offset = Vec2::new(angle.cos(), angle.sin()) * 25.;
+                ev_spawn.send(EnemySpawnEvent {
+                    kind: EnemyKind::Splitling,
+                    position: ev.position + offset,
+                });
+            }
+        }
+    }
+}
+
+pub fn how_to_spawn_enemies(mut commands: Commands, mut ev_spawn: EventReader<EnemySpawnEvent>) {
     // send an event to get this to spawn an enemy
 
     // spawn an enemy for each event
-    for _ in ev_spawn.iter() {
-        let mut rng = rand::thread_rng();
-        let spawn_pos = Vec2::new(rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0))
-            .normalize_or_zero()
-            .extend(0.)
-            * 200.;
-        commands
-            .spawn_bundle(SpriteBundle {
-                sprite: Sprite {
-                    color: Color::RED,
-                    custom_size: Some(Vec2::new(35., 35.)),
-                    ..default()
-                },
-                transform: Transform::from_translation(spawn_pos),
-                ..default()
-            })
-            .insert(Enemy)
-            .insert(health::Health::new(2))
-            .insert(RigidBody::Dynamic)
-            .insert(LockedAxes::ROTATION_LOCKED)
-            .insert(Collider::cuboid(35.0 / 2.0, 35.0 / 2.0));
+    for ev in ev_spawn.iter() {
+        spawn_enemy(&mut commands, ev.kind, ev.position);
     }
 }
+
+// a fresh enemy of a kind, with everything it needs to behave like one
+pub fn spawn_enemy<'w, 's, 'a>(
+    commands: &'a mut Commands<'w, 's>,
+    kind: EnemyKind,
+    position: Vec2,
+) -> EntityCommands<'w, 's, 'a> {
+    match kind {
+        EnemyKind::Walker => {
+            let mut enemy = spawn_enemy_body(commands, kind, position, 2);
+            enemy.insert(Chase { speed: 100. });
+            enemy
+        }
+        EnemyKind::Charger => {
+            let mut enemy = spawn_enemy_body(commands, kind, position, 2);
+            enemy.insert(Charge {
+                walk_speed: 70.,
+                dash_speed: 650.,
+                range: 300.,
+                state: ChargeState::Approaching,
+            });
+            enemy
+        }
+        EnemyKind::Shooter => {
+            let mut enemy = spawn_enemy_body(commands, kind, position, 1);
+            enemy.insert(RangedAttack {
+                speed: 80.,
+                range: 400.,
+                fire_timer: Timer::from_seconds(1.5, true),
+            });
+            enemy
+        }
+        EnemyKind::Tank => {
+            // shrugs off a point of every hit and half of explosions
+            let mut enemy = spawn_enemy_body(commands, kind, position, 10);
+            enemy
+                .insert(Chase { speed: 60. })
+                .insert(Armor(1))
+                .insert(Resistances(vec![(DamageKind::Explosion, 0.5)]));
+            enemy
+        }
+        EnemyKind::Splitter => {
+            let mut enemy = spawn_enemy_body(commands, kind, position, 4);
+            enemy
+                .insert(Chase { speed: 80. })
+                .insert(Splitter { children: 3 });
+            enemy
+        }
+        EnemyKind::Splitling => {
+            let mut enemy = spawn_enemy_body(commands, kind, position, 1);
+            enemy.insert(Chase { speed: 140. });
+            enemy
+        }
+    }
+}
+
+// the parts every enemy has
+// each kind adds the components for how it behaves on top
+fn spawn_enemy_body<'w, 's, 'a>(
+    commands: &'a mut Commands<'w, 's>,
+    kind: EnemyKind,
+    position: Vec2,
+    hp: u32,
+) -> EntityCommands<'w, 's, 'a> {
+    let (color, size) = kind.look();
+    let mut enemy = commands.spawn_bundle(SpriteBundle {
+        sprite: Sprite {
+            color,
+            custom_size: Some(Vec2::new(size, size)),
+            ..default()
+        },
+        transform: Transform::from_translation(position.extend(0.)),
+        ..default()
+    });
+    enemy
+        .insert(Enemy(kind))
+        .insert(health::Health::new(hp))
+        .insert(DeathDelay(0.25))
+        .insert(ContactDamage(10))
+        .insert(RigidBody::Dynamic)
+        .insert(LockedAxes::ROTATION_LOCKED)
+        .insert(Collider::cuboid(size / 2.0, size / 2.0));
+    enemy
+}
@@ -0,0 +1,332 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    asset::AssetPlugin,
+    hierarchy::HierarchyPlugin,
+    prelude::*,
+    time::{create_time_channels, TimeSender},
+    transform::TransformPlugin,
+};
+
+#[cfg(test)]
+use bevy::ecs::system::CommandQueue;
+
+#[cfg(test)]
+use crate::enemy::{self, EnemyKind};
+
+use crate::{
+    enemy::Enemy,
+    health::Dying,
+    input::{self, InputScript, PlayerInput},
+    level::SelectedLevel,
+    player::LocalPlayers,
+    replay::{self, Replay, ReplayPlayback},
+    score::Score,
+    snapshot::{self, Snapshot},
+    state::AppState,
+    stats::RunStats,
+    tick::{self, RunSeed, TickApp, TickStage},
+    wave::WaveDirector,
+    GamePlugin, Player,
+};
+
+// runs the game without a window, renderer or real input
+// MinimalPlugins + rapier, with the input coming from an InputScript
+//
+// time is faked so a frame is always 1/60s no matter how fast the machine is.
+// the time plugin listens on a channel for the render world's time,
+// so we pretend to be the render world and send it the time we want
+pub struct HeadlessApp {
+    pub app: App,
+    time_sender: TimeSender,
+    now: Instant,
+    frame_time: Duration,
+}
+
+impl HeadlessApp {
+    pub fn new(script: InputScript) -> Self {
+        let mut headless = HeadlessApp::empty(AppState::Playing);
+        headless
+            .app
+            .insert_resource(script)
+            .add_tick_system_to_stage(TickStage::Start, input::scripted_input);
+        headless
+    }
+
+    // the input comes from the replay instead of a script
+    pub fn replay(replay: Replay) -> Self {
+        let mut headless = HeadlessApp::empty(AppState::Playing);
+        replay::play(&mut headless.app, replay);
+        headless
+    }
+
+    // nothing fills PlayerInputs, whoever uses this has to
+    pub fn empty(state: AppState) -> Self {
+        let (time_sender, time_receiver) = create_time_channels();
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(TransformPlugin)
+            .add_plugin(HierarchyPlugin)
+            .add_plugin(AssetPlugin)
+            .add_plugin(GamePlugin)
+            .add_tick_state(state)
+            .insert_resource(time_receiver);
+
+        HeadlessApp {
+            app,
+            time_sender,
+            now: Instant::now(),
+            // exactly one tick a frame
+            frame_time: tick::TICK,
+        }
+    }
+
+    pub fn update(&mut self) {
+        self.now += self.frame_time;
+        self.time_sender
+            .0
+            .send(self.now)
+            .expect("time channel closed");
+        self.app.update();
+    }
+
+    pub fn run_frames(&mut self, frames: u32) {
+        for _ in 0..frames {
+            self.update();
+        }
+    }
+
+    pub fn world(&mut self) -> &mut World {
+        &mut self.app.world
+    }
+}
+
+// strafe around the middle of the arena shooting at whatever is in front
+pub fn demo_script() -> InputScript {
+    let step = |movement: Vec2, aim: Vec2| PlayerInput {
+        movement,
+        aim,
+        fire: true,
+        ..default()
+    };
+
+    InputScript::default()
+        .then(60, step(Vec2::X, Vec2::new(0., 400.)))
+        .then(60, step(Vec2::Y, Vec2::new(-400., 0.)))
+        .then(60, step(-Vec2::X, Vec2::new(0., -400.)))
+        .then(60, step(-Vec2::Y, Vec2::new(400., 0.)))
+}
+
+// batch simulate a few fights and print what happened
+// with a stats dir, each fight's RunStats is written there as json
+// level is a path under assets like levels/pillars.level.ron
+// with a seed, fight n uses seed + n so a batch can be rerun exactly
+// with a snapshot, every fight carries on from it instead of starting fresh
+// with more than one player they all follow the demo script
+pub fn run(
+    fights: u32,
+    frames: u32,
+    stats_dir: Option<PathBuf>,
+    level: Option<String>,
+    seed: Option<u64>,
+    snapshot: Option<PathBuf>,
+    players: usize,
+) {
+    let snapshot = match snapshot.map(|path| (Snapshot::load(&path), path)) {
+        Some((Ok(snapshot), _)) => Some(snapshot),
+        Some((Err(err), path)) => {
+            eprintln!("Couldn't read snapshot {:?}: {}", path, err);
+            return;
+        }
+        None => None,
+    };
+
+    for fight in 0..fights {
+        let mut sim = HeadlessApp::new(demo_script());
+        if let Some(level) = &level {
+            sim.app.insert_resource(SelectedLevel(level.clone()));
+        }
+        sim.app
+            .insert_resource(RunSeed(seed.map(|seed| seed.wrapping_add(fight as u64))))
+            .insert_resource(LocalPlayers(players));
+        if let Some(snapshot) = &snapshot {
+            snapshot::load(&mut sim.app, snapshot.clone());
+        }
+        sim.run_frames(frames);
+
+        let world = sim.world();
+        let wave = world.resource::<WaveDirector>().wave;
+        let enemies = world
+            .query_filtered::<(), (With<Enemy>, Without<Dying>)>()
+            .iter(world)
+            .len();
+        let score = world.resource::<Score>().points;
+        let mut players: Vec<(usize, Vec2)> = world
+            .query::<(&Player, &Transform)>()
+            .iter(world)
+            .map(|(player, transform)| (player.0, transform.translation.truncate()))
+            .collect();
+        players.sort_by_key(|(slot, _)| *slot);
+        let players: Vec<Vec2> = players.into_iter().map(|(_, pos)| pos).collect();
+
+        println!(
+            "Fight {:?}: {:?} frames, reached wave {:?}, {:?} enemies alive, score {:?}, players at {:?}",
+            fight, frames, wave, enemies, score, players
+        );
+
+        if let Some(dir) = &stats_dir {
+            let mut stats = world.resource_mut::<RunStats>();
+            stats.finish(wave, score);
+            match stats.export(dir) {
+                Ok(path) => println!("Fight {:?} stats written to {:?}", fight, path),
+                Err(err) => eprintln!("Couldn't write fight {:?} stats: {}", fight, err),
+            }
+        }
+    }
+}
+
+// play a replay as fast as possible and say whether it still matches
+pub fn check_replay(path: &Path) {
+    let replay = match Replay::load(path) {
+        Ok(replay) => replay,
+        Err(err) => {
+            eprintln!("Couldn't read replay {:?}: {}", path, err);
+            return;
+        }
+    };
+    let ticks = replay.ticks();
+
+    let mut sim = HeadlessApp::replay(replay);
+    // frames before the assets load don't tick, so go by the replay
+    while !sim.world().resource::<ReplayPlayback>().finished() {
+        sim.update();
+    }
+
+    match sim.world().resource::<ReplayPlayback>().desync {
+        Some(tick) => println!("Replay desynced at tick {:?} of {:?}", tick, ticks),
+        None => println!("Replay matched for all {:?} ticks", ticks),
+    }
+}
+
+#[cfg(test)]
+impl HeadlessApp {
+    // frames before the assets load don't tick, so go by GameTime instead
+    pub fn run_ticks(&mut self, ticks: u64) {
+        let target = self.world().resource::<tick::GameTime>().tick() + ticks;
+        let started = Instant::now();
+        while self.world().resource::<tick::GameTime>().tick() < target {
+            assert!(
+                started.elapsed() < Duration::from_secs(30),
+                "the assets never loaded"
+            );
+            self.update();
+        }
+    }
+
+    pub fn spawn_enemy(&mut self, kind: EnemyKind, position: Vec2) -> Entity {
+        let world = self.world();
+        let mut queue = CommandQueue::default();
+        let entity = {
+            let mut commands = Commands::new(&mut queue, world);
+            enemy::spawn_enemy(&mut commands, kind, position).id()
+        };
+        queue.apply(world);
+        entity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::Health;
+
+    fn fight(script: InputScript, seed: u64) -> HeadlessApp {
+        let mut sim = HeadlessApp::new(script);
+        sim.app.insert_resource(RunSeed(Some(seed)));
+        sim
+    }
+
+    fn player_position(sim: &mut HeadlessApp) -> Vec2 {
+        let world = sim.world();
+        let mut q_player = world.query_filtered::<&Transform, With<Player>>();
+        q_player.single(world).translation.truncate()
+    }
+
+    #[test]
+    fn player_follows_the_script() {
+        let right = PlayerInput {
+            movement: Vec2::X,
+            ..default()
+        };
+        let mut sim = fight(
+            InputScript::default().then(10, default()).then(60, right),
+            1,
+        );
+
+        sim.run_ticks(10);
+        let start = player_position(&mut sim);
+        sim.run_ticks(30);
+        let end = player_position(&mut sim);
+
+        // 30 ticks at 350 a second is 175, enemies might have bumped into them a bit
+        assert!(end.x - start.x > 100., "moved from {} to {}", start, end);
+    }
+
+    #[test]
+    fn shooting_hurts_enemies() {
+        let target = Vec2::new(250., 0.);
+        let shoot = PlayerInput {
+            aim: target,
+            fire: true,
+            ..default()
+        };
+        let mut sim = fight(
+            InputScript::default().then(10, default()).then(120, shoot),
+            2,
+        );
+
+        sim.run_ticks(10);
+        let enemy = sim.spawn_enemy(EnemyKind::Walker, target);
+        sim.run_ticks(120);
+
+        // a walker dies in a couple of hits, so it might be gone already
+        let hurt = match sim.world().get::<Health>(enemy) {
+            Some(hp) => hp.current() < hp.max(),
+            None => true,
+        };
+        assert!(hurt, "the enemy in front of the player never got hit");
+    }
+
+    // what should come out the same in two runs with the same seed
+    fn summary(sim: &mut HeadlessApp) -> (u64, u32, Vec<(u32, u32)>) {
+        let world = sim.world();
+        let score = world.resource::<Score>().points;
+        let wave = world.resource::<WaveDirector>().wave;
+        let mut positions: Vec<(u32, u32)> = world
+            .query_filtered::<&Transform, Or<(With<Player>, With<Enemy>)>>()
+            .iter(world)
+            .map(|transform| {
+                (
+                    transform.translation.x.to_bits(),
+                    transform.translation.y.to_bits(),
+                )
+            })
+            .collect();
+        positions.sort_unstable();
+        (score, wave, positions)
+    }
+
+    #[test]
+    fn same_seed_plays_the_same() {
+        let mut first = fight(demo_script(), 3);
+        let mut second = fight(demo_script(), 3);
+        first.run_ticks(600);
+        second.run_ticks(600);
+
+        assert_eq!(summary(&mut first), summary(&mut second));
+    }
+}
@@ -1,9 +1,26 @@
 use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Component)]
+use crate::{
+    enemy::{Enemy, EnemyKind},
+    player::Dead,
+    state::AppState,
+    tick::{GameRng, GameTime, TickApp},
+    Player,
+};
+
+#[derive(Component, Serialize, Deserialize, Clone)]
 pub struct Health {
     max_health: u32,
     current_health: u32,
+    // whoever hit it last, gets the credit for the kill
+    // only matters for the tick it dies on, so snapshots leave it out
+    #[serde(skip)]
+    last_damage_source: Option<Entity>,
+    // how far past max_health overheal() can go
+    overheal_cap: u32,
 }
 
 impl Health {
@@ -11,10 +28,60 @@ impl Health {
         Health {
             max_health: hp,
             current_health: hp,
+            last_damage_source: None,
+            overheal_cap: 0,
+        }
+    }
+
+    // Health::new(100).with_overheal(50)
+    pub fn with_overheal(mut self, cap: u32) -> Self {
+        self.overheal_cap = cap;
+        self
+    }
+
+    // up to max_health
+    // doesn't take away overheal you already have
+    pub fn heal(&mut self, amount: u32) {
+        if self.current_health >= self.max_health {
+            return;
+        }
+        self.current_health = (self.current_health + amount).min(self.max_health);
+    }
+
+    // like heal, but can go over max_health by up to the overheal cap
+    pub fn overheal(&mut self, amount: u32) {
+        let cap = self.max_health + self.overheal_cap;
+        if self.current_health >= cap {
+            return;
+        }
+        self.current_health = (self.current_health + amount).min(cap);
+    }
+
+    // keeps the same fraction of health
+    // 50/100 with set_max(200) is 100/200.
+    // nothing changes max health mid fight yet
+    #[allow(dead_code)]
+    pub fn set_max(&mut self, max: u32) {
+        if self.max_health == 0 {
+            self.max_health = max;
+            self.current_health = max;
+            return;
+        }
+
+        let fraction = self.current_health as f32 / self.max_health as f32;
+        self.max_health = max;
+        self.current_health = ((fraction * max as f32).round() as u32).min(max + self.overheal_cap);
+        // rounding down to 0 would kill it
+        if fraction > 0. {
+            self.current_health = self.current_health.max(1);
         }
     }
 
-    pub fn take_damage(&mut self, damage: u32) {
+    // only damage::apply_damage should call this
+    // everything else sends a DamageEvent
+    pub fn take_damage(&mut self, damage: u32, source: Option<Entity>) {
+        self.last_damage_source = source;
+
         if damage > self.current_health {
             self.current_health = 0;
         } else {
@@ -26,23 +93,380 @@ impl Health {
         // and max doesn't like that
         //self.current_health = max(0, self.current_health - damage);
     }
+
+    pub fn current(&self) -> u32 {
+        self.current_health
+    }
+
+    pub fn max(&self) -> u32 {
+        self.max_health
+    }
+
+    // how much is over max_health
+    pub fn overhealed(&self) -> u32 {
+        self.current_health.saturating_sub(self.max_health)
+    }
+
+    // 0 to 1, for health bars. overheal doesn't show
+    pub fn fraction(&self) -> f32 {
+        if self.max_health == 0 {
+            return 0.;
+        }
+        (self.current_health as f32 / self.max_health as f32).min(1.)
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current_health == 0
+    }
 }
 
 pub struct HealthPlugin;
 
 impl Plugin for HealthPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(death);
+        app.add_tick_event::<DeathEvent>().add_tick_system_set(
+            SystemSet::on_update(AppState::Playing)
+                .with_system(death)
+                .with_system(dying.before(death))
+                .with_system(death_event.after(death))
+                .with_system(regenerate)
+                .with_system(recharge_shields)
+                .with_system(decay_overheal)
+                .with_system(drop_health_pickups.after(death))
+                .with_system(check_health_pickup),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntityKind {
+    Player,
+    Enemy(EnemyKind),
+    Other,
+}
+
+// sent once when something runs out of health.
+// the entity sticks around (Dying) for at least a frame after this
+// so anything reacting to it can still look it up.
+// hook loot, score, splitting, etc onto this
+pub struct DeathEvent {
+    pub entity: Entity,
+    pub position: Vec2,
+    pub killer: Option<Entity>,
+    pub kind: EntityKind,
+}
+
+// how long to play the death animation before despawning
+// without this it's gone the frame after it dies
+#[derive(Component)]
+pub struct DeathDelay(pub f32);
+
+// dead but not despawned yet. it can't be hit or touch anything
+// so leave it out of queries for things that are still fighting
+#[derive(Component)]
+pub struct Dying {
+    timer: Timer,
+}
+
+// the player isn't despawned, they get player::Dead instead
+pub fn death(
+    mut commands: Commands,
+    q_health: Query<
+        (
+            Entity,
+            &Health,
+            &Transform,
+            Option<&Enemy>,
+            Option<&Player>,
+            Option<&DeathDelay>,
+        ),
+        (Without<Dying>, Without<Dead>),
+    >,
+    mut ev_death: EventWriter<DeathEvent>,
+) {
+    for (ent, hp, transform, enemy, player, delay) in q_health.iter() {
+        if !hp.is_dead() {
+            continue;
+        }
+
+        let kind = match (enemy, player) {
+            (_, Some(_)) => EntityKind::Player,
+            (Some(enemy), None) => EntityKind::Enemy(enemy.0),
+            (None, None) => EntityKind::Other,
+        };
+
+        ev_death.send(DeathEvent {
+            entity: ent,
+            position: transform.translation.truncate(),
+            killer: hp.last_damage_source,
+            kind,
+        });
+
+        if kind == EntityKind::Player {
+            commands.entity(ent).insert(Dead);
+        } else {
+            let seconds = delay.map(|delay| delay.0).unwrap_or(0.);
+            commands
+                .entity(ent)
+                .insert(Dying {
+                    timer: Timer::from_seconds(seconds, false),
+                })
+                .remove::<Collider>();
+        }
+    }
+}
+
+// shrink and fade out, then despawn
+fn dying(
+    mut commands: Commands,
+    mut q_dying: Query<(Entity, &mut Dying, &mut Transform, Option<&mut Sprite>)>,
+    time: Res<GameTime>,
+) {
+    for (ent, mut dying, mut transform, sprite) in q_dying.iter_mut() {
+        dying.timer.tick(time.delta());
+        if dying.timer.finished() {
+            commands.entity(ent).despawn_recursive();
+            continue;
+        }
+
+        let left = dying.timer.percent_left();
+        transform.scale = Vec3::splat(left);
+        if let Some(mut sprite) = sprite {
+            sprite.color.set_a(left);
+        }
+    }
+}
+
+fn death_event(mut ev_death: EventReader<DeathEvent>) {
+    for ev in ev_death.iter() {
+        match ev.killer {
+            Some(killer) => println!("{:?} killed {:?} ({:?})", killer, ev.entity, ev.kind),
+            None => println!("{:?} died ({:?})", ev.entity, ev.kind),
+        }
     }
 }
 
-//pub struct death_event;
-// ref to entity?
+// heals slowly once you've gone a while without getting hurt
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct Regeneration {
+    pub per_second: f32,
+    // seconds without damage before it starts
+    pub delay: f32,
+    since_damage: f32,
+    // the part of a point healed so far
+    progress: f32,
+}
+
+impl Regeneration {
+    pub fn new(per_second: f32, delay: f32) -> Self {
+        Regeneration {
+            per_second,
+            delay,
+            since_damage: 0.,
+            progress: 0.,
+        }
+    }
+
+    // called by damage::apply_damage
+    pub fn interrupt(&mut self) {
+        self.since_damage = 0.;
+        self.progress = 0.;
+    }
+}
+
+// soaks up damage before Health does
+// and fills back up on its own after a while without getting hurt
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct Shield {
+    pub max: u32,
+    pub current: u32,
+    pub recharge_per_second: f32,
+    // seconds without damage before it starts
+    pub delay: f32,
+    since_damage: f32,
+    progress: f32,
+}
+
+impl Shield {
+    pub fn new(max: u32, recharge_per_second: f32, delay: f32) -> Self {
+        Shield {
+            max,
+            current: max,
+            recharge_per_second,
+            delay,
+            since_damage: 0.,
+            progress: 0.,
+        }
+    }
+
+    // returns what got through
+    pub fn absorb(&mut self, damage: u32) -> u32 {
+        self.since_damage = 0.;
+        self.progress = 0.;
+
+        let absorbed = damage.min(self.current);
+        self.current -= absorbed;
+        damage - absorbed
+    }
+}
+
+// a point at a time once enough has built up
+fn tick_towards(progress: &mut f32, per_second: f32, delta: f32) -> u32 {
+    *progress += per_second * delta;
+    let whole = progress.floor();
+    *progress -= whole;
+    whole as u32
+}
+
+fn regenerate(
+    mut q_regen: Query<(&mut Health, &mut Regeneration), (Without<Dead>, Without<Dying>)>,
+    time: Res<GameTime>,
+) {
+    for (mut hp, mut regen) in q_regen.iter_mut() {
+        let regen = &mut *regen;
+        regen.since_damage += time.delta_seconds();
+        if regen.since_damage < regen.delay || hp.is_dead() {
+            continue;
+        }
+
+        let amount = tick_towards(&mut regen.progress, regen.per_second, time.delta_seconds());
+        if amount > 0 {
+            hp.heal(amount);
+        }
+    }
+}
 
-fn death(mut commands: Commands, q_health: Query<(Entity, &Health)>) {
-    for (ent, hp) in q_health.iter() {
-        if hp.current_health == 0 {
-            commands.entity(ent).despawn();
+fn recharge_shields(
+    mut q_shield: Query<&mut Shield, (Without<Dead>, Without<Dying>)>,
+    time: Res<GameTime>,
+) {
+    for mut shield in q_shield.iter_mut() {
+        let shield = &mut *shield;
+        shield.since_damage += time.delta_seconds();
+        if shield.since_damage < shield.delay || shield.current >= shield.max {
+            continue;
         }
+
+        let amount = tick_towards(
+            &mut shield.progress,
+            shield.recharge_per_second,
+            time.delta_seconds(),
+        );
+        shield.current = (shield.current + amount).min(shield.max);
+    }
+}
+
+// overheal drains back down to max, a point every half second
+fn decay_overheal(
+    mut q_health: Query<&mut Health>,
+    time: Res<GameTime>,
+    mut timer: Local<Option<Timer>>,
+) {
+    let timer = timer.get_or_insert_with(|| Timer::from_seconds(0.5, true));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    for mut hp in q_health.iter_mut() {
+        if hp.overhealed() > 0 {
+            hp.current_health -= 1;
+        }
+    }
+}
+
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct HealthPickup {
+    amount: u32,
+}
+
+pub const HEALTH_PICKUP_COLOR: Color = Color::rgb(0.2, 0.9, 0.3);
+
+// enemies drop one this often
+const HEALTH_DROP_CHANCE: f64 = 0.08;
+
+fn drop_health_pickups(
+    mut commands: Commands,
+    mut ev_death: EventReader<DeathEvent>,
+    mut rng: ResMut<GameRng>,
+) {
+    for ev in ev_death.iter() {
+        if !matches!(ev.kind, EntityKind::Enemy(_)) || !rng.gen_bool(HEALTH_DROP_CHANCE) {
+            continue;
+        }
+
+        spawn_health_pickup(&mut commands, ev.position, HealthPickup { amount: 25 });
+    }
+}
+
+pub fn spawn_health_pickup(commands: &mut Commands, position: Vec2, pickup: HealthPickup) {
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: HEALTH_PICKUP_COLOR,
+                custom_size: Some(Vec2::new(15., 15.)),
+                ..default()
+            },
+            transform: Transform::from_translation(position.extend(0.)),
+            ..default()
+        })
+        .insert(pickup)
+        .insert(RigidBody::Dynamic)
+        .insert(Collider::ball(7.5))
+        .insert(Sensor);
+}
+
+// health pickups can overheal
+fn check_health_pickup(
+    mut commands: Commands,
+    rapier_context: Res<RapierContext>,
+    q_pickups: Query<(Entity, &HealthPickup)>,
+    mut q_player: Query<(Entity, &mut Health), (With<Player>, Without<Dead>)>,
+) {
+    // two players on the same pickup, the first one gets it
+    let mut taken = Vec::new();
+
+    for (player, mut hp) in q_player.iter_mut() {
+        for (pickup, health_pickup) in q_pickups.iter() {
+            if taken.contains(&pickup) {
+                continue;
+            }
+            if rapier_context.intersection_pair(pickup, player) == Some(true) {
+                hp.overheal(health_pickup.amount);
+                commands.entity(pickup).despawn();
+                taken.push(pickup);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_max_keeps_the_fraction() {
+        let mut hp = Health::new(100);
+        hp.take_damage(50, None);
+        hp.set_max(200);
+        assert_eq!((hp.current(), hp.max()), (100, 200));
+        hp.set_max(50);
+        assert_eq!((hp.current(), hp.max()), (25, 50));
+    }
+
+    #[test]
+    fn set_max_doesnt_kill() {
+        let mut hp = Health::new(100);
+        hp.take_damage(99, None);
+        hp.set_max(10);
+        assert_eq!(hp.current(), 1);
+    }
+
+    #[test]
+    fn set_max_keeps_overheal_under_the_cap() {
+        let mut hp = Health::new(100).with_overheal(20);
+        hp.overheal(20);
+        // 120/100 would be 240/200, the cap is still only 20 over
+        hp.set_max(200);
+        assert_eq!(hp.current(), 220);
     }
 }
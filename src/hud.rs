@@ -0,0 +1,455 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::{bevy_egui::EguiContext, egui};
+
+use crate::{
+    cartridge::CartridgeInventory,
+    damage::DamageDealt,
+    health::{Health, Shield},
+    lerp::{lerp, lerp_vec2},
+    player::{self, LocalPlayers},
+    score::Score,
+    shooting::{Gun, GunState, ImmediateReloadEvent, ShootError, ShootErrorEvent, ShotgunGauge},
+    state::AppState,
+    tick::{GameTime, TickApp},
+    weapon::{Weapon, WeaponDef},
+    Player,
+};
+
+// health bars, damage numbers, the player's health and ammo.
+// only added when there's a window.
+// there's no font in assets so text goes through egui
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AmmoFlashes>()
+            .add_system(add_health_bars)
+            .add_system(update_health_bars)
+            .add_tick_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(spawn_damage_numbers)
+                    .with_system(float_damage_numbers)
+                    .with_system(flash_perfect_shots)
+                    .with_system(flash_refused_shots),
+            )
+            .add_tick_system_set(
+                SystemSet::on_exit(AppState::Playing).with_system(clear_damage_numbers),
+            )
+            // still drawn while paused, just not moving
+            .add_system(draw_damage_numbers)
+            .add_system(player_hud)
+            .add_system(ammo_hud)
+            .add_system(score_hud);
+    }
+}
+
+const BAR_WIDTH: f32 = 40.;
+const BAR_HEIGHT: f32 = 5.;
+
+// the red part of the bar, a child of the background
+#[derive(Component)]
+struct HealthBarFill;
+
+// the background, a child of the entity with Health
+#[derive(Component)]
+struct HealthBar;
+
+#[derive(Component)]
+struct DamageNumber {
+    amount: u32,
+    crit: bool,
+    start: Vec2,
+    timer: Timer,
+}
+
+// world-space bars over everything with Health except the player
+// the player's is in player_hud
+fn add_health_bars(
+    mut commands: Commands,
+    q_new: Query<(Entity, Option<&Sprite>), (Added<Health>, Without<Player>)>,
+) {
+    for (entity, sprite) in q_new.iter() {
+        // just above the top of the sprite
+        let height = sprite
+            .and_then(|sprite| sprite.custom_size)
+            .map(|size| size.y)
+            .unwrap_or(40.);
+
+        commands.entity(entity).with_children(|parent| {
+            parent
+                .spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        color: Color::rgba(0., 0., 0., 0.6),
+                        custom_size: Some(Vec2::new(BAR_WIDTH, BAR_HEIGHT)),
+                        ..default()
+                    },
+                    transform: Transform::from_xyz(0., height / 2. + 8., 1.),
+                    // full health bars are just clutter
+                    visibility: Visibility { is_visible: false },
+                    ..default()
+                })
+                .insert(HealthBar)
+                .with_children(|bar| {
+                    bar.spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color: Color::RED,
+                            custom_size: Some(Vec2::new(BAR_WIDTH, BAR_HEIGHT)),
+                            ..default()
+                        },
+                        transform: Transform::from_xyz(0., 0., 0.1),
+                        ..default()
+                    })
+                    .insert(HealthBarFill);
+                });
+        });
+    }
+}
+
+fn update_health_bars(
+    q_health: Query<(&Health, &Children), Changed<Health>>,
+    mut q_bars: Query<(&mut Visibility, &Children), With<HealthBar>>,
+    mut q_fill: Query<&mut Transform, With<HealthBarFill>>,
+) {
+    for (hp, children) in q_health.iter() {
+        for child in children.iter() {
+            let (mut visibility, bar_children) = match q_bars.get_mut(*child) {
+                Ok(bar) => bar,
+                Err(_) => continue,
+            };
+            let fraction = hp.fraction();
+            visibility.is_visible = fraction < 1.;
+
+            for fill in bar_children.iter() {
+                if let Ok(mut transform) = q_fill.get_mut(*fill) {
+                    // shrink towards the left edge
+                    transform.scale.x = fraction;
+                    transform.translation.x = -BAR_WIDTH / 2. * (1. - fraction);
+                }
+            }
+        }
+    }
+}
+
+// from DamageDealt rather than shooting::BulletHitEvent, it has the damage
+// after armor, crits, etc and covers explosions and contact damage too.
+// for bullets the position is where the bullet hit
+fn spawn_damage_numbers(mut commands: Commands, mut ev_dealt: EventReader<DamageDealt>) {
+    for ev in ev_dealt.iter() {
+        if ev.amount == 0 {
+            continue;
+        }
+
+        commands
+            .spawn()
+            .insert(Transform::from_translation(ev.position.extend(0.)))
+            .insert(DamageNumber {
+                amount: ev.amount,
+                crit: ev.crit,
+                start: ev.position,
+                timer: Timer::from_seconds(0.8, false),
+            });
+    }
+}
+
+// rise up and fade out
+fn float_damage_numbers(
+    mut commands: Commands,
+    mut q_numbers: Query<(Entity, &mut DamageNumber, &mut Transform)>,
+    time: Res<GameTime>,
+) {
+    for (entity, mut number, mut transform) in q_numbers.iter_mut() {
+        if number.timer.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let t = number.timer.percent();
+        let end = number.start + Vec2::new(0., 40.);
+        transform.translation = lerp_vec2(number.start, end, t).extend(0.);
+    }
+}
+
+fn clear_damage_numbers(mut commands: Commands, q_numbers: Query<Entity, With<DamageNumber>>) {
+    for entity in q_numbers.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
+fn draw_damage_numbers(
+    mut egui_context: ResMut<EguiContext>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    q_numbers: Query<(&DamageNumber, &Transform)>,
+) {
+    let (camera, camera_transform) = match q_camera.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    let screen_height = match camera.logical_viewport_size() {
+        Some(size) => size.y,
+        None => return,
+    };
+
+    let painter = egui_context.ctx_mut().layer_painter(egui::LayerId::new(
+        egui::Order::Background,
+        egui::Id::new("damage_numbers"),
+    ));
+
+    for (number, transform) in q_numbers.iter() {
+        // bevy's viewport starts at the bottom, egui's at the top
+        let pos = match camera.world_to_viewport(camera_transform, transform.translation) {
+            Some(pos) => egui::pos2(pos.x, screen_height - pos.y),
+            None => continue,
+        };
+
+        let alpha = lerp(255., 0., number.timer.percent()) as u8;
+        let (color, size) = if number.crit {
+            (
+                egui::Color32::from_rgba_unmultiplied(255, 200, 0, alpha),
+                22.,
+            )
+        } else {
+            (
+                egui::Color32::from_rgba_unmultiplied(255, 255, 255, alpha),
+                16.,
+            )
+        };
+
+        painter.text(
+            pos,
+            egui::Align2::CENTER_CENTER,
+            number.amount.to_string(),
+            egui::FontId::proportional(size),
+            color,
+        );
+    }
+}
+
+// each player's hud goes in its own column
+const HUD_WIDTH: f32 = 200.;
+
+fn hud_x(player: &Player) -> f32 {
+    10. + player.0 as f32 * (HUD_WIDTH + 20.)
+}
+
+// in co-op, whose hud it is in their colour
+fn player_label(ui: &mut egui::Ui, player: &Player, local_players: &LocalPlayers) {
+    if local_players.0 <= 1 {
+        return;
+    }
+    let color = player::player_color(player.0);
+    ui.colored_label(
+        egui::Color32::from_rgb(
+            (color.r() * 255.) as u8,
+            (color.g() * 255.) as u8,
+            (color.b() * 255.) as u8,
+        ),
+        format!("Player {}", player.0 + 1),
+    );
+}
+
+fn player_hud(
+    mut egui_context: ResMut<EguiContext>,
+    q_player: Query<(&Player, &Health, Option<&Shield>)>,
+    local_players: Res<LocalPlayers>,
+) {
+    for (player, hp, shield) in q_player.iter() {
+        egui::Area::new(format!("player_hud_{}", player.0))
+            .anchor(egui::Align2::LEFT_TOP, egui::vec2(hud_x(player), 10.))
+            .show(egui_context.ctx_mut(), |ui| {
+                ui.set_width(HUD_WIDTH);
+                player_label(ui, player, &local_players);
+
+                let mut text = format!("{}/{}", hp.current(), hp.max());
+                if hp.overhealed() > 0 {
+                    text = format!("{} (+{})", text, hp.overhealed());
+                }
+                // progress bars are filled with the selection colour
+                ui.visuals_mut().selection.bg_fill = egui::Color32::from_rgb(180, 30, 30);
+                ui.add(egui::ProgressBar::new(hp.fraction()).text(text));
+
+                if let Some(shield) = shield {
+                    let fraction = if shield.max == 0 {
+                        0.
+                    } else {
+                        shield.current as f32 / shield.max as f32
+                    };
+                    ui.visuals_mut().selection.bg_fill = egui::Color32::from_rgb(40, 120, 200);
+                    ui.add(
+                        egui::ProgressBar::new(fraction)
+                            .text(format!("{}/{}", shield.current, shield.max)),
+                    );
+                }
+            });
+    }
+}
+
+// how long the perfect shot flash lasts
+const FLASH_SECONDS: f32 = 0.6;
+const REFUSED_FLASH_SECONDS: f32 = 0.3;
+
+// seconds left on each player's flash, by slot.
+// set from the tick, a frame that runs a few ticks
+// could miss the event if ammo_hud read it itself
+#[derive(Default)]
+struct AmmoFlashes {
+    perfect: Vec<f32>,
+    // pulling the trigger on an empty or reloading gun
+    refused: Vec<f32>,
+}
+
+fn flash_perfect_shots(
+    mut ev_reload: EventReader<ImmediateReloadEvent>,
+    q_player: Query<&Player>,
+    mut flashes: ResMut<AmmoFlashes>,
+) {
+    for ev in ev_reload.iter() {
+        if let Ok(player) = q_player.get(ev.shooter) {
+            if flashes.perfect.len() <= player.0 {
+                flashes.perfect.resize(player.0 + 1, 0.);
+            }
+            flashes.perfect[player.0] = FLASH_SECONDS;
+        }
+    }
+}
+
+fn flash_refused_shots(
+    mut ev_shoot_error: EventReader<ShootErrorEvent>,
+    q_player: Query<&Player>,
+    mut flashes: ResMut<AmmoFlashes>,
+) {
+    for ev in ev_shoot_error.iter() {
+        // waiting between shots isn't worth pointing out
+        if ev.error == ShootError::ShotCooldown {
+            continue;
+        }
+        if let Ok(player) = q_player.get(ev.shooter) {
+            if flashes.refused.len() <= player.0 {
+                flashes.refused.resize(player.0 + 1, 0.);
+            }
+            flashes.refused[player.0] = REFUSED_FLASH_SECONDS;
+        }
+    }
+}
+
+// shells left, reload progress, what each shotgun shot hit
+// and a flash when a perfect shot gives a free reload
+fn ammo_hud(
+    mut egui_context: ResMut<EguiContext>,
+    q_player: Query<(
+        &Player,
+        &Gun,
+        Option<&Weapon>,
+        Option<&ShotgunGauge>,
+        Option<&CartridgeInventory>,
+    )>,
+    local_players: Res<LocalPlayers>,
+    weapons: Res<Assets<WeaponDef>>,
+    mut flashes: ResMut<AmmoFlashes>,
+    time: Res<Time>,
+) {
+    let flashes = &mut *flashes;
+    for flash in flashes.perfect.iter_mut().chain(flashes.refused.iter_mut()) {
+        *flash = (*flash - time.delta_seconds()).max(0.);
+    }
+
+    for (player, gun, weapon, gauge, carts) in q_player.iter() {
+        let flash = flashes.perfect.get(player.0).copied().unwrap_or(0.);
+        let flash_amount = flash / FLASH_SECONDS;
+        let refused = flashes.refused.get(player.0).copied().unwrap_or(0.);
+        let refused_amount = refused / REFUSED_FLASH_SECONDS;
+
+        egui::Area::new(format!("ammo_hud_{}", player.0))
+            .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(hud_x(player), -10.))
+            .show(egui_context.ctx_mut(), |ui| {
+                ui.set_width(HUD_WIDTH);
+                player_label(ui, player, &local_players);
+
+                if let Some(def) = weapon.and_then(|weapon| weapons.get(&weapon.0)) {
+                    ui.label(&def.name);
+                }
+
+                // one box per shell, yellow while the perfect shot flash is on.
+                // the empty ones go red when the trigger is refused
+                let loaded = egui::Color32::from_rgb(
+                    lerp(220., 255., flash_amount) as u8,
+                    lerp(160., 230., flash_amount) as u8,
+                    lerp(60., 0., flash_amount) as u8,
+                );
+                ui.horizontal(|ui| {
+                    for shell in 0..gun.clip_size {
+                        let (rect, _) =
+                            ui.allocate_exact_size(egui::vec2(8., 16.), egui::Sense::hover());
+                        let color = if shell < gun.shots_left {
+                            loaded
+                        } else {
+                            egui::Color32::from_rgb(lerp(60., 200., refused_amount) as u8, 60, 60)
+                        };
+                        ui.painter().rect_filled(rect, 1., color);
+                    }
+                });
+
+                if gun.state == GunState::Reloading {
+                    ui.add(
+                        egui::ProgressBar::new(gun.reload_timer.percent())
+                            .text("Reloading")
+                            .desired_width(HUD_WIDTH),
+                    );
+                }
+
+                // a row of dots per shot in the clip
+                // green hit, red missed, grey still flying
+                if let Some(gauge) = gauge {
+                    for shot in gauge.shots().iter().take(gun.clip_size as usize) {
+                        if shot.pellets().is_empty() {
+                            continue;
+                        }
+                        ui.horizontal(|ui| {
+                            for pellet in shot.pellets() {
+                                let (rect, _) = ui.allocate_exact_size(
+                                    egui::vec2(10., 10.),
+                                    egui::Sense::hover(),
+                                );
+                                let color = match pellet {
+                                    Some(true) => egui::Color32::from_rgb(60, 200, 60),
+                                    Some(false) => egui::Color32::from_rgb(200, 60, 60),
+                                    None => egui::Color32::from_gray(120),
+                                };
+                                ui.painter().circle_filled(rect.center(), 4., color);
+                            }
+                        });
+                    }
+                }
+
+                if flash > 0. {
+                    let alpha = lerp(0., 255., flash_amount) as u8;
+                    ui.colored_label(
+                        egui::Color32::from_rgba_unmultiplied(255, 230, 0, alpha),
+                        "PERFECT!",
+                    );
+                }
+
+                if let Some(cart) = carts.and_then(|carts| carts.loaded()) {
+                    ui.label(format!("{:?} cartridge ({})", cart.kind, cart.power));
+                }
+            });
+    }
+}
+
+fn score_hud(
+    mut egui_context: ResMut<EguiContext>,
+    score: Res<Score>,
+    state: Res<State<AppState>>,
+) {
+    if *state.current() == AppState::Menu {
+        return;
+    }
+
+    egui::Area::new("score_hud")
+        .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-10., 10.))
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.heading(score.points.to_string());
+            if score.combo > 1 {
+                ui.label(format!("{} combo  x{:.1}", score.combo, score.multiplier()));
+            }
+        });
+}
@@ -0,0 +1,643 @@
+use std::{collections::BTreeMap, fs, marker::PhantomData, path::PathBuf};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::{player::LocalPlayers, MouseWorldPos, Player};
+
+// gameplay systems read this instead of Input<KeyCode>/Input<MouseButton>
+// so they don't care where the input came from.
+// the windowed game fills it from the keyboard, mouse and gamepads
+// the headless app fills it from an InputScript
+// and a replay fills it from the file
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct PlayerInput {
+    pub movement: Vec2,
+    // world position the player is aiming at
+    pub aim: Vec2,
+    pub fire: bool,
+    // weapon slot to switch to this tick
+    pub switch_weapon: Option<usize>,
+    // pressed since the last tick
+    pub reload: bool,
+    // pressed since the last tick
+    pub swap_cartridge: bool,
+    // pressed since the last tick
+    pub pause: bool,
+}
+
+// one PlayerInput per local player, indexed by Player.0
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct PlayerInputs(pub Vec<PlayerInput>);
+
+impl PlayerInputs {
+    // a player without an entry stands still
+    pub fn get(&self, player: &Player) -> PlayerInput {
+        self.0.get(player.0).copied().unwrap_or_default()
+    }
+
+    pub fn any(&self, f: impl Fn(&PlayerInput) -> bool) -> bool {
+        self.0.iter().any(f)
+    }
+
+    // grows to fit, anything past players is dropped
+    pub fn resize(&mut self, players: usize) {
+        self.0.resize(players, PlayerInput::default());
+    }
+}
+
+// the things a player can do, each bound to any number of keys/buttons.
+// moving and aiming can also come from the gamepad sticks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    Fire,
+    Reload,
+    SwapCartridge,
+    Pause,
+    Weapon1,
+    Weapon2,
+    Weapon3,
+    Weapon4,
+}
+
+impl Action {
+    pub const ALL: [Action; 12] = [
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::Fire,
+        Action::Reload,
+        Action::SwapCartridge,
+        Action::Pause,
+        Action::Weapon1,
+        Action::Weapon2,
+        Action::Weapon3,
+        Action::Weapon4,
+    ];
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    // on whichever gamepad the player has
+    Gamepad(GamepadButtonType),
+}
+
+impl Binding {
+    // the keyboard and mouse count as one device
+    fn is_gamepad(&self) -> bool {
+        matches!(self, Binding::Gamepad(_))
+    }
+}
+
+// what a local player is playing with.
+// on your own it's everything, like before co-op.
+// with more players the first gets the keyboard and mouse
+// and everyone else gets a gamepad each, in the order they were connected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputDevice {
+    Everything,
+    KeyboardMouse,
+    Gamepad(Gamepad),
+}
+
+impl InputDevice {
+    fn has_keyboard(&self) -> bool {
+        matches!(self, InputDevice::Everything | InputDevice::KeyboardMouse)
+    }
+
+    fn has_gamepad(&self, gamepad: Gamepad) -> bool {
+        match self {
+            InputDevice::Everything => true,
+            InputDevice::KeyboardMouse => false,
+            InputDevice::Gamepad(own) => *own == gamepad,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Stick {
+    Left,
+    Right,
+}
+
+impl Stick {
+    fn axes(&self) -> (GamepadAxisType, GamepadAxisType) {
+        match self {
+            Stick::Left => (GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY),
+            Stick::Right => (GamepadAxisType::RightStickX, GamepadAxisType::RightStickY),
+        }
+    }
+}
+
+// saved as bindings.ron in the user's config folder
+// edit the file or rebind from the pause menu
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct InputBindings {
+    pub buttons: BTreeMap<Action, Vec<Binding>>,
+    pub move_stick: Stick,
+    pub aim_stick: Stick,
+    // stick values smaller than this are ignored
+    pub dead_zone: f32,
+    // aiming with a stick aims at a point this far from the player
+    pub stick_aim_distance: f32,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        use Binding::*;
+
+        let buttons = [
+            (Action::MoveUp, vec![Key(KeyCode::W)]),
+            (Action::MoveDown, vec![Key(KeyCode::S)]),
+            (Action::MoveLeft, vec![Key(KeyCode::A)]),
+            (Action::MoveRight, vec![Key(KeyCode::D)]),
+            (
+                Action::Fire,
+                vec![
+                    Mouse(MouseButton::Left),
+                    Gamepad(GamepadButtonType::RightTrigger2),
+                ],
+            ),
+            (
+                Action::Reload,
+                vec![Key(KeyCode::R), Gamepad(GamepadButtonType::West)],
+            ),
+            (
+                Action::SwapCartridge,
+                vec![Key(KeyCode::Q), Gamepad(GamepadButtonType::North)],
+            ),
+            (
+                Action::Pause,
+                vec![Key(KeyCode::Escape), Gamepad(GamepadButtonType::Start)],
+            ),
+            (
+                Action::Weapon1,
+                vec![Key(KeyCode::Key1), Gamepad(GamepadButtonType::DPadLeft)],
+            ),
+            (
+                Action::Weapon2,
+                vec![Key(KeyCode::Key2), Gamepad(GamepadButtonType::DPadUp)],
+            ),
+            (
+                Action::Weapon3,
+                vec![Key(KeyCode::Key3), Gamepad(GamepadButtonType::DPadRight)],
+            ),
+            (
+                Action::Weapon4,
+                vec![Key(KeyCode::Key4), Gamepad(GamepadButtonType::DPadDown)],
+            ),
+        ];
+
+        InputBindings {
+            buttons: buttons.into_iter().collect(),
+            move_stick: Stick::Left,
+            aim_stick: Stick::Right,
+            dead_zone: 0.2,
+            stick_aim_distance: 300.,
+        }
+    }
+}
+
+impl InputBindings {
+    fn path() -> Option<PathBuf> {
+        Some(
+            dirs::config_dir()?
+                .join("capsule_shooter")
+                .join("bindings.ron"),
+        )
+    }
+
+    // writes the defaults out the first time so there's a file to edit
+    pub fn load_or_default() -> Self {
+        let path = match InputBindings::path() {
+            Some(path) => path,
+            None => return InputBindings::default(),
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(text) => match ron::from_str::<InputBindings>(&text) {
+                Ok(mut bindings) => {
+                    // actions added since the file was saved get their default keys
+                    for (action, default) in InputBindings::default().buttons {
+                        bindings.buttons.entry(action).or_insert(default);
+                    }
+                    bindings
+                }
+                Err(err) => {
+                    eprintln!("Couldn't read {:?}, using default bindings: {}", path, err);
+                    InputBindings::default()
+                }
+            },
+            Err(_) => {
+                let bindings = InputBindings::default();
+                if let Err(err) = bindings.save() {
+                    eprintln!("Couldn't save bindings: {}", err);
+                }
+                bindings
+            }
+        }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = match InputBindings::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let text = ron::ser::to_string_pretty(self, PrettyConfig::default())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        fs::write(path, text)
+    }
+
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.buttons
+            .get(&action)
+            .map(|bindings| bindings.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // only replaces what's bound on the same kind of device,
+    // so rebinding a key keeps the gamepad button and the other way round
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        let same_device = |old: &Binding| old.is_gamepad() == binding.is_gamepad();
+        let bindings = self.buttons.entry(action).or_default();
+        // in the old one's place, so the menu lists them in the same order
+        let index = bindings
+            .iter()
+            .position(same_device)
+            .unwrap_or(bindings.len());
+        bindings.retain(|old| !same_device(old));
+        bindings.insert(index, binding);
+    }
+}
+
+// all the raw input the bindings can point at
+#[derive(SystemParam)]
+pub struct Devices<'w, 's> {
+    keyboard: Res<'w, Input<KeyCode>>,
+    mouse: Res<'w, Input<MouseButton>>,
+    gamepads: Res<'w, Gamepads>,
+    gamepad_buttons: Res<'w, Input<GamepadButton>>,
+    gamepad_axes: Res<'w, Axis<GamepadAxis>>,
+    #[system_param(ignore)]
+    _marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> Devices<'w, 's> {
+    // None if there's no gamepad for them yet
+    fn device(&self, slot: usize, players: usize) -> Option<InputDevice> {
+        if players <= 1 {
+            return Some(InputDevice::Everything);
+        }
+        if slot == 0 {
+            return Some(InputDevice::KeyboardMouse);
+        }
+
+        // the set of gamepads has no order, ids go up as they connect
+        let mut gamepads: Vec<Gamepad> = self.gamepads.iter().copied().collect();
+        gamepads.sort_by_key(|gamepad| gamepad.id);
+        gamepads.get(slot - 1).copied().map(InputDevice::Gamepad)
+    }
+
+    fn pressed(&self, device: InputDevice, binding: &Binding) -> bool {
+        match binding {
+            Binding::Key(key) => device.has_keyboard() && self.keyboard.pressed(*key),
+            Binding::Mouse(button) => device.has_keyboard() && self.mouse.pressed(*button),
+            Binding::Gamepad(button) => self.gamepads.iter().any(|gamepad| {
+                device.has_gamepad(*gamepad)
+                    && self
+                        .gamepad_buttons
+                        .pressed(GamepadButton::new(*gamepad, *button))
+            }),
+        }
+    }
+
+    fn just_pressed(&self, device: InputDevice, binding: &Binding) -> bool {
+        match binding {
+            Binding::Key(key) => device.has_keyboard() && self.keyboard.just_pressed(*key),
+            Binding::Mouse(button) => device.has_keyboard() && self.mouse.just_pressed(*button),
+            Binding::Gamepad(button) => self.gamepads.iter().any(|gamepad| {
+                device.has_gamepad(*gamepad)
+                    && self
+                        .gamepad_buttons
+                        .just_pressed(GamepadButton::new(*gamepad, *button))
+            }),
+        }
+    }
+
+    fn action_pressed(
+        &self,
+        device: InputDevice,
+        bindings: &InputBindings,
+        action: Action,
+    ) -> bool {
+        bindings
+            .bindings(action)
+            .iter()
+            .any(|binding| self.pressed(device, binding))
+    }
+
+    fn action_just_pressed(
+        &self,
+        device: InputDevice,
+        bindings: &InputBindings,
+        action: Action,
+    ) -> bool {
+        bindings
+            .bindings(action)
+            .iter()
+            .any(|binding| self.just_pressed(device, binding))
+    }
+
+    // the first of the device's gamepads pushing the stick past the dead zone
+    fn stick(&self, device: InputDevice, stick: Stick, dead_zone: f32) -> Option<Vec2> {
+        let (x_axis, y_axis) = stick.axes();
+        self.gamepads.iter().find_map(|gamepad| {
+            if !device.has_gamepad(*gamepad) {
+                return None;
+            }
+            let value = Vec2::new(
+                self.gamepad_axes
+                    .get(GamepadAxis::new(*gamepad, x_axis))
+                    .unwrap_or(0.),
+                self.gamepad_axes
+                    .get(GamepadAxis::new(*gamepad, y_axis))
+                    .unwrap_or(0.),
+            );
+            if value.length() > dead_zone {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+
+    // whatever was pressed this frame, for rebinding
+    fn any_just_pressed(&self) -> Option<Binding> {
+        if let Some(key) = self.keyboard.get_just_pressed().next() {
+            return Some(Binding::Key(*key));
+        }
+        if let Some(button) = self.mouse.get_just_pressed().next() {
+            return Some(Binding::Mouse(*button));
+        }
+        self.gamepad_buttons
+            .get_just_pressed()
+            .next()
+            .map(|button| Binding::Gamepad(button.button_type))
+    }
+}
+
+// fill each player's PlayerInput from their device through the bindings
+pub fn device_input(
+    devices: Devices,
+    bindings: Res<InputBindings>,
+    rebinding: Res<Rebinding>,
+    local_players: Res<LocalPlayers>,
+    mouse_pos: Res<MouseWorldPos>,
+    mut ev_cursor: EventReader<CursorMoved>,
+    q_player: Query<(&Player, &Transform)>,
+    mut player_inputs: ResMut<PlayerInputs>,
+    mut stick_aim: Local<Vec<Option<Vec2>>>,
+) {
+    let players = local_players.0;
+    player_inputs.resize(players);
+    stick_aim.resize(players, None);
+
+    // the key being rebound shouldn't also do its old job
+    if rebinding.0.is_some() {
+        for input in player_inputs.0.iter_mut() {
+            *input = PlayerInput {
+                aim: input.aim,
+                ..default()
+            };
+        }
+        return;
+    }
+
+    let cursor_moved = ev_cursor.iter().count() > 0;
+
+    for slot in 0..players {
+        let input = &mut player_inputs.0[slot];
+        let device = match devices.device(slot, players) {
+            Some(device) => device,
+            // their gamepad isn't plugged in
+            None => {
+                *input = PlayerInput {
+                    aim: input.aim,
+                    ..default()
+                };
+                continue;
+            }
+        };
+
+        let pressed = |action| devices.action_pressed(device, &bindings, action);
+        let just_pressed = |action| devices.action_just_pressed(device, &bindings, action);
+
+        let mut move_input = Vec2::ZERO;
+
+        if pressed(Action::MoveLeft) {
+            move_input.x = -1.;
+        } else if pressed(Action::MoveRight) {
+            move_input.x = 1.;
+        }
+
+        if pressed(Action::MoveDown) {
+            move_input.y = -1.;
+        } else if pressed(Action::MoveUp) {
+            move_input.y = 1.;
+        }
+
+        if let Some(stick) = devices.stick(device, bindings.move_stick, bindings.dead_zone) {
+            move_input = stick;
+        }
+
+        // aim with whichever was used last, the mouse or the stick
+        // the stick keeps pointing the same way when you let go of it
+        if let Some(stick) = devices.stick(device, bindings.aim_stick, bindings.dead_zone) {
+            stick_aim[slot] = Some(stick.normalize());
+        }
+        if cursor_moved && device.has_keyboard() {
+            stick_aim[slot] = None;
+        }
+
+        // without a mouse, face right until the stick's been touched
+        let aim_dir = match stick_aim[slot] {
+            Some(dir) => Some(dir),
+            None if device.has_keyboard() => None,
+            None => Some(Vec2::X),
+        };
+        let player_pos = q_player
+            .iter()
+            .find(|(player, _)| player.0 == slot)
+            .map(|(_, transform)| transform.translation.truncate());
+
+        input.aim = match (aim_dir, player_pos) {
+            (Some(dir), Some(pos)) => pos + dir * bindings.stick_aim_distance,
+            _ => mouse_pos.0,
+        };
+
+        input.movement = move_input;
+        input.fire = pressed(Action::Fire);
+
+        // a frame can go by without a tick, so presses are kept
+        // until a tick has seen them. see clear_presses
+        input.reload |= just_pressed(Action::Reload);
+        input.swap_cartridge |= just_pressed(Action::SwapCartridge);
+        input.pause |= just_pressed(Action::Pause);
+
+        let switch_weapon = [
+            Action::Weapon1,
+            Action::Weapon2,
+            Action::Weapon3,
+            Action::Weapon4,
+        ]
+        .iter()
+        .position(|action| just_pressed(*action));
+        if switch_weapon.is_some() {
+            input.switch_weapon = switch_weapon;
+        }
+    }
+}
+
+// at the end of every tick
+pub fn clear_presses(mut player_inputs: ResMut<PlayerInputs>) {
+    for input in player_inputs.0.iter_mut() {
+        input.reload = false;
+        input.swap_cartridge = false;
+        input.pause = false;
+        input.switch_weapon = None;
+    }
+}
+
+// Some(action) while waiting for a key to bind to it
+#[derive(Default)]
+pub struct Rebinding(pub Option<Action>);
+
+pub fn capture_rebind(
+    devices: Devices,
+    mut rebinding: ResMut<Rebinding>,
+    mut bindings: ResMut<InputBindings>,
+) {
+    let action = match rebinding.0 {
+        Some(action) => action,
+        None => return,
+    };
+
+    if let Some(binding) = devices.any_just_pressed() {
+        bindings.rebind(action, binding);
+        rebinding.0 = None;
+        if let Err(err) = bindings.save() {
+            eprintln!("Couldn't save bindings: {}", err);
+        }
+    }
+}
+
+// hold an input for a number of frames
+#[derive(Clone, Copy, Debug)]
+pub struct ScriptStep {
+    pub frames: u32,
+    pub input: PlayerInput,
+}
+
+// a list of inputs to play back one after the other
+// once it runs out, the player stands still and stops shooting
+pub struct InputScript {
+    steps: Vec<ScriptStep>,
+    frame: u32,
+}
+
+impl InputScript {
+    pub fn new(steps: Vec<ScriptStep>) -> Self {
+        InputScript { steps, frame: 0 }
+    }
+
+    // chain steps like
+    // InputScript::default().then(60, input).then(30, other_input)
+    pub fn then(mut self, frames: u32, input: PlayerInput) -> Self {
+        self.steps.push(ScriptStep { frames, input });
+        self
+    }
+
+    pub fn current(&self) -> PlayerInput {
+        let mut frames_left = self.frame;
+        for step in self.steps.iter() {
+            if frames_left < step.frames {
+                return step.input;
+            }
+            frames_left -= step.frames;
+        }
+        PlayerInput::default()
+    }
+}
+
+impl Default for InputScript {
+    fn default() -> Self {
+        InputScript::new(Vec::new())
+    }
+}
+
+// every local player follows the same script
+pub fn scripted_input(
+    mut script: ResMut<InputScript>,
+    local_players: Res<LocalPlayers>,
+    mut player_inputs: ResMut<PlayerInputs>,
+) {
+    *player_inputs = PlayerInputs(vec![script.current(); local_players.0]);
+    script.frame += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebinding_a_key_keeps_the_gamepad() {
+        let mut bindings = InputBindings::default();
+        bindings.rebind(Action::Reload, Binding::Key(KeyCode::T));
+        assert_eq!(
+            bindings.bindings(Action::Reload),
+            &[
+                Binding::Key(KeyCode::T),
+                Binding::Gamepad(GamepadButtonType::West)
+            ]
+        );
+    }
+
+    #[test]
+    fn mouse_and_keys_are_one_device() {
+        let mut bindings = InputBindings::default();
+        bindings.rebind(Action::Fire, Binding::Key(KeyCode::Space));
+        assert_eq!(
+            bindings.bindings(Action::Fire),
+            &[
+                Binding::Key(KeyCode::Space),
+                Binding::Gamepad(GamepadButtonType::RightTrigger2)
+            ]
+        );
+    }
+
+    #[test]
+    fn rebinding_the_gamepad_keeps_the_key() {
+        let mut bindings = InputBindings::default();
+        bindings.rebind(Action::Pause, Binding::Gamepad(GamepadButtonType::Select));
+        assert_eq!(
+            bindings.bindings(Action::Pause),
+            &[
+                Binding::Key(KeyCode::Escape),
+                Binding::Gamepad(GamepadButtonType::Select)
+            ]
+        );
+    }
+}
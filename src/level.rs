@@ -0,0 +1,365 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadState, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use bevy_rapier2d::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    health::{DeathDelay, Health},
+    state::AppState,
+    tick::{TickApp, TickAssets},
+    wall::{Breakable, Door, OneWay, Reflective},
+    Player, Wall,
+};
+
+pub struct LevelPlugin;
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Level>()
+            .init_asset_loader::<LevelLoader>()
+            .init_resource::<SelectedLevel>()
+            .add_tick_event::<LevelBuiltEvent>()
+            .add_startup_system(preload_levels)
+            .add_tick_system_set(SystemSet::on_enter(AppState::Playing).with_system(load_level))
+            .add_tick_system_set(SystemSet::on_update(AppState::Playing).with_system(build_level));
+    }
+}
+
+// the arenas you can pick from the main menu
+// files live in assets/levels
+pub const LEVEL_FILES: [&str; 2] = ["levels/arena.level.ron", "levels/pillars.level.ron"];
+
+// everything that makes up an arena, loaded from a .level.ron file
+// positions are in pixels from the middle of the screen,
+// rotations are in degrees anticlockwise
+// any field left out of the file uses the default (the old hardcoded 1920x1080 box)
+#[derive(Deserialize, TypeUuid, Debug, Clone)]
+#[uuid = "c3a1e7d2-5b9f-4e08-8f3a-2d6b1c4e9a70"]
+#[serde(default)]
+pub struct Level {
+    pub name: String,
+    pub player_spawn: Vec2,
+    pub walls: Vec<WallDef>,
+    // used by waves that don't have their own spawn points
+    // if there are none either, enemies spawn in a ring around the middle
+    pub enemy_spawns: Vec<Vec2>,
+    // cartridge pickups show up at one of these
+    // if there are none, anywhere inside the old box
+    pub pickup_spawns: Vec<Vec2>,
+    pub props: Vec<PropDef>,
+}
+
+impl Level {
+    // co-op players line up to the right of player_spawn
+    pub fn spawn_for(&self, player: &Player) -> Vec2 {
+        self.player_spawn + Vec2::new(70. * player.0 as f32, 0.)
+    }
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        let wall = |position: Vec2, size: Vec2| WallDef {
+            position,
+            size,
+            ..default()
+        };
+
+        Level {
+            name: "Arena".to_string(),
+            player_spawn: Vec2::ZERO,
+            walls: vec![
+                wall(Vec2::new(0., 540.), Vec2::new(1920., 50.)),
+                wall(Vec2::new(0., -540.), Vec2::new(1920., 50.)),
+                wall(Vec2::new(960., 0.), Vec2::new(50., 1080.)),
+                wall(Vec2::new(-960., 0.), Vec2::new(50., 1080.)),
+            ],
+            enemy_spawns: Vec::new(),
+            pickup_spawns: Vec::new(),
+            props: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WallDef {
+    pub position: Vec2,
+    pub size: Vec2,
+    pub rotation: f32,
+    // breaks after taking this much damage. Some(20)
+    pub health: Option<u32>,
+    // bullets bounce off it
+    pub reflective: bool,
+    // bullets can pass through going the way the top of the wall faces,
+    // so rotate it to point the way they're let through
+    pub one_way: bool,
+    // Some((after_wave: 2, stay_open: true))
+    pub door: Option<DoorDef>,
+}
+
+impl Default for WallDef {
+    fn default() -> Self {
+        WallDef {
+            position: Vec2::ZERO,
+            size: Vec2::new(50., 50.),
+            rotation: 0.,
+            health: None,
+            reflective: false,
+            one_way: false,
+            door: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DoorDef {
+    pub after_wave: u32,
+    pub stay_open: bool,
+}
+
+impl Default for DoorDef {
+    fn default() -> Self {
+        DoorDef {
+            after_wave: 1,
+            stay_open: false,
+        }
+    }
+}
+
+impl WallDef {
+    // so you can tell what a wall does by looking at it
+    fn color(&self) -> Color {
+        if self.door.is_some() {
+            Color::rgb(0.1, 0.35, 0.1)
+        } else if self.health.is_some() {
+            Color::rgb(0.45, 0.3, 0.15)
+        } else if self.reflective {
+            Color::rgb(0.6, 0.7, 0.8)
+        } else if self.one_way {
+            Color::rgb(0.2, 0.2, 0.5)
+        } else {
+            Color::BLACK
+        }
+    }
+}
+
+// scenery. solid ones block movement but bullets go through them,
+// use a wall if they shouldn't
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PropDef {
+    pub position: Vec2,
+    pub size: Vec2,
+    pub rotation: f32,
+    // rgb from 0 to 1
+    pub color: (f32, f32, f32),
+    pub solid: bool,
+}
+
+impl Default for PropDef {
+    fn default() -> Self {
+        PropDef {
+            position: Vec2::ZERO,
+            size: Vec2::new(50., 50.),
+            rotation: 0.,
+            color: (0.5, 0.5, 0.5),
+            solid: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct LevelLoader;
+
+impl AssetLoader for LevelLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let level = ron::de::from_bytes::<Level>(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(level));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["level.ron"]
+    }
+}
+
+// which file the next fight is played in
+pub struct SelectedLevel(pub String);
+
+impl Default for SelectedLevel {
+    fn default() -> Self {
+        SelectedLevel(LEVEL_FILES[0].to_string())
+    }
+}
+
+// the level the current fight is in
+pub struct CurrentLevel {
+    pub handle: Handle<Level>,
+    built: bool,
+}
+
+impl CurrentLevel {
+    pub fn get<'a>(&self, levels: &'a Assets<Level>) -> Option<&'a Level> {
+        levels.get(&self.handle)
+    }
+
+    // the walls are up and the player is on the spawn point
+    pub fn is_built(&self) -> bool {
+        self.built
+    }
+}
+
+// sent the first time the level is built for a fight,
+// not when it's rebuilt after the file changes
+pub struct LevelBuiltEvent {
+    pub level: Handle<Level>,
+}
+
+#[derive(Component)]
+pub struct Prop;
+
+fn preload_levels(
+    asset_server: Res<AssetServer>,
+    selected: Res<SelectedLevel>,
+    mut tick_assets: ResMut<TickAssets>,
+) {
+    for path in LEVEL_FILES.iter().copied().chain([selected.0.as_str()]) {
+        tick_assets.add(&asset_server.load::<Level, _>(path));
+    }
+}
+
+fn load_level(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    selected: Res<SelectedLevel>,
+) {
+    commands.insert_resource(CurrentLevel {
+        handle: asset_server.load(&selected.0),
+        built: false,
+    });
+}
+
+// the level is built once the file has loaded
+// and rebuilt if it changes on disk, so walls can be moved while playing
+pub fn build_level(
+    mut commands: Commands,
+    mut current: ResMut<CurrentLevel>,
+    levels: Res<Assets<Level>>,
+    asset_server: Res<AssetServer>,
+    mut ev_asset: EventReader<AssetEvent<Level>>,
+    mut ev_built: EventWriter<LevelBuiltEvent>,
+    q_geometry: Query<Entity, Or<(With<Wall>, With<Prop>)>>,
+    mut q_player: Query<(&Player, &mut Transform)>,
+    fallback: Local<Level>,
+) {
+    let modified = ev_asset.iter().any(|ev| match ev {
+        AssetEvent::Modified { handle } => *handle == current.handle,
+        _ => false,
+    });
+    if current.built && !modified {
+        return;
+    }
+
+    let level = match levels.get(&current.handle) {
+        Some(level) => level,
+        // a broken file still gets you the old box to play in
+        None if asset_server.get_load_state(&current.handle) == LoadState::Failed => &*fallback,
+        None => return,
+    };
+
+    for entity in q_geometry.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    for wall in level.walls.iter() {
+        build_wall(&mut commands, wall);
+    }
+    for prop in level.props.iter() {
+        build_prop(&mut commands, prop);
+    }
+
+    if !current.built {
+        current.built = true;
+        for (player, mut transform) in q_player.iter_mut() {
+            transform.translation = level.spawn_for(player).extend(transform.translation.z);
+        }
+        ev_built.send(LevelBuiltEvent {
+            level: current.handle.clone(),
+        });
+    }
+}
+
+fn transform_2d(position: Vec2, rotation: f32, z: f32) -> Transform {
+    Transform {
+        translation: position.extend(z),
+        rotation: Quat::from_rotation_z(rotation.to_radians()),
+        ..default()
+    }
+}
+
+fn build_wall(commands: &mut Commands, wall: &WallDef) {
+    let mut entity = commands.spawn_bundle(SpriteBundle {
+        sprite: Sprite {
+            color: wall.color(),
+            custom_size: Some(wall.size),
+            ..default()
+        },
+        transform: transform_2d(wall.position, wall.rotation, 0.),
+        ..default()
+    });
+    entity
+        .insert(Collider::cuboid(wall.size.x * 0.5, wall.size.y * 0.5))
+        .insert(RigidBody::Fixed)
+        .insert(Wall);
+
+    if let Some(health) = wall.health {
+        entity
+            .insert(Health::new(health))
+            .insert(Breakable)
+            .insert(DeathDelay(0.2));
+    }
+    if wall.reflective {
+        entity.insert(Reflective);
+    }
+    if wall.one_way {
+        entity.insert(OneWay);
+    }
+    if let Some(door) = &wall.door {
+        entity.insert(Door {
+            after_wave: door.after_wave,
+            stay_open: door.stay_open,
+            open: false,
+        });
+    }
+}
+
+fn build_prop(commands: &mut Commands, prop: &PropDef) {
+    let (r, g, b) = prop.color;
+    let mut entity = commands.spawn_bundle(SpriteBundle {
+        sprite: Sprite {
+            color: Color::rgb(r, g, b),
+            custom_size: Some(prop.size),
+            ..default()
+        },
+        // behind everything else
+        transform: transform_2d(prop.position, prop.rotation, -1.),
+        ..default()
+    });
+    entity.insert(Prop);
+
+    if prop.solid {
+        entity
+            .insert(Collider::cuboid(prop.size.x * 0.5, prop.size.y * 0.5))
+            .insert(RigidBody::Fixed);
+    }
+}
@@ -1,43 +1,255 @@
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+
 use bevy::{
+    asset::AssetServerSettings,
+    input::InputSystem,
     prelude::*,
     render::camera::{RenderTarget, ScalingMode},
 };
+use bevy_inspector_egui::bevy_egui::EguiPlugin;
 use bevy_rapier2d::prelude::*;
+use tick::TickApp;
 
 mod cartridge;
+mod damage;
 mod enemy;
+mod headless;
 mod health;
+mod hud;
+mod input;
+mod lerp;
+mod level;
+mod menu;
+mod net;
+mod player;
+mod replay;
+mod score;
 mod shooting;
+mod snapshot;
+mod state;
+mod stats;
+mod tick;
+mod wall;
+mod wave;
+mod weapon;
 
+// there's only one mouse, it aims for whoever has the keyboard.
+// everyone's aim ends up in their own PlayerInput
 struct MouseWorldPos(Vec2);
 
-#[derive(Component)]
-pub struct Player;
+// which local player, 0 is the first. indexes input::PlayerInputs
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Player(pub usize);
 
 #[derive(Component)]
 pub struct Wall;
 
 fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
-        .add_plugin(RapierDebugRenderPlugin::default())
-        .add_plugin(shooting::ShootingPlugin)
-        .add_plugin(enemy::EnemyPlugin)
-        .add_plugin(health::HealthPlugin)
-        .add_plugin(cartridge::CartridgePlugin)
+    // cargo run -- --headless [--fights n] [--frames n] [--stats-dir path] [--level path] [--seed n] [--players n]
+    // runs fights without a window and prints the results
+    // --seed also works without --headless, every run then plays out the same
+    // --replay path watches a recorded run, or with --headless checks it still plays the same
+    // --snapshot path carries on from a saved fight, with or without --headless
+    // --server port [--level path] [--seed n] hosts online play without a window
+    // --connect ip:port joins one, --loopback [port] hosts and joins on this machine
+    let args: Vec<String> = std::env::args().collect();
+    let seed = arg_value(&args, "--seed");
+    if args.iter().any(|arg| arg == "--server") {
+        let port = arg_value(&args, "--server").unwrap_or(net::DEFAULT_PORT);
+        let level = arg_value(&args, "--level");
+        match net::bind(([0, 0, 0, 0], port).into()) {
+            Ok(socket) => net::run_server(socket, level, seed),
+            Err(err) => eprintln!("Couldn't listen on port {:?}: {}", port, err),
+        }
+        return;
+    }
+    let replay: Option<PathBuf> = arg_value(&args, "--replay");
+    let snapshot_path: Option<PathBuf> = arg_value(&args, "--snapshot");
+    if args.iter().any(|arg| arg == "--headless") {
+        if let Some(path) = replay {
+            headless::check_replay(&path);
+            return;
+        }
+        let fights = arg_value(&args, "--fights").unwrap_or(1);
+        let frames = arg_value(&args, "--frames").unwrap_or(600);
+        let stats_dir = arg_value(&args, "--stats-dir");
+        let level = arg_value(&args, "--level");
+        let players = arg_value(&args, "--players").unwrap_or(1);
+        headless::run(
+            fights,
+            frames,
+            stats_dir,
+            level,
+            seed,
+            snapshot_path,
+            players,
+        );
+        return;
+    }
+
+    let server: Option<SocketAddr> = if args.iter().any(|arg| arg == "--loopback") {
+        let port = arg_value(&args, "--loopback").unwrap_or(net::DEFAULT_PORT);
+        let level = arg_value(&args, "--level");
+        match net::loopback(port, level, seed) {
+            Ok(addr) => Some(addr),
+            Err(err) => {
+                eprintln!(
+                    "Couldn't start the loopback server on port {:?}: {}",
+                    port, err
+                );
+                return;
+            }
+        }
+    } else {
+        arg_value(&args, "--connect")
+    };
+    let client = match server.map(net::NetClient::connect) {
+        Some(Ok(client)) => Some(client),
+        Some(Err(err)) => {
+            eprintln!("Couldn't open a socket to connect with: {}", err);
+            return;
+        }
+        None => None,
+    };
+
+    let replay = replay.and_then(|path| match replay::Replay::load(&path) {
+        Ok(replay) => Some(replay),
+        Err(err) => {
+            eprintln!("Couldn't read replay {:?}: {}", path, err);
+            None
+        }
+    });
+    let snapshot = snapshot_path.and_then(|path| match snapshot::Snapshot::load(&path) {
+        Ok(snapshot) => Some(snapshot),
+        Err(err) => {
+            eprintln!("Couldn't read snapshot {:?}: {}", path, err);
+            None
+        }
+    });
+
+    let mut app = App::new();
+    app.insert_resource(tick::RunSeed(seed))
+        // edit a weapon file while the game is running and it updates
+        .insert_resource(AssetServerSettings {
+            watch_for_changes: true,
+            ..default()
+        })
+        .add_plugins(DefaultPlugins);
+
+    // online, the fight runs on the server and there's no menu
+    match client {
+        Some(client) => {
+            app.insert_resource(client).add_plugin(net::NetClientPlugin);
+        }
+        None => {
+            app.add_plugin(GamePlugin)
+                .add_tick_state(match (&replay, &snapshot) {
+                    (None, None) => state::AppState::Menu,
+                    _ => state::AppState::Playing,
+                })
+                .add_plugin(menu::MenuPlugin);
+        }
+    }
+
+    app.add_plugin(RapierDebugRenderPlugin::default())
+        .add_plugin(EguiPlugin)
+        .add_plugin(hud::HudPlugin)
         .add_startup_system(setup)
-        .add_startup_system(spawn_player)
-        .add_startup_system(spawn_bounds)
-        //.add_startup_system(spawn_enemies)
         .insert_resource(MouseWorldPos(Vec2::ZERO))
-        .insert_resource(RapierConfiguration {
-            gravity: Vec2::ZERO,
-            ..default()
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            update_mouse_position.after(InputSystem),
+        )
+        .insert_resource(input::InputBindings::load_or_default())
+        .insert_resource(score::HighScores::load())
+        .init_resource::<input::Rebinding>()
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            input::device_input.after(update_mouse_position),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            input::capture_rebind.after(input::device_input),
+        );
+
+    if server.is_none() {
+        if let Some(replay) = replay {
+            replay::play(&mut app, replay);
+        }
+        if let Some(snapshot) = snapshot {
+            snapshot::load(&mut app, snapshot);
+        }
+    }
+
+    // run stats, replays and snapshots go next to the high scores
+    if let Some(dir) = dirs::data_dir() {
+        app.insert_resource(stats::StatsExport {
+            dir: dir.join("capsule_shooter").join("runs"),
+        })
+        .insert_resource(replay::ReplayExport {
+            dir: dir.join("capsule_shooter").join("replays"),
         })
-        .add_system(player_movement)
-        .add_system(update_mouse_position)
-        .run();
+        .insert_resource(snapshot::SnapshotExport {
+            dir: dir.join("capsule_shooter").join("snapshots"),
+        });
+    }
+
+    app.run();
+}
+
+fn arg_value<T: FromStr>(args: &[String], name: &str) -> Option<T> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.get(index + 1)?.parse().ok()
+}
+
+// everything the game needs to run a fight
+// the window, camera, menus and real input are added on top of this in main
+// the headless app adds a scripted input instead
+// whoever adds this also needs to add_tick_state with the AppState to start in
+pub struct GamePlugin;
+
+impl Plugin for GamePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(tick::TickPlugin)
+            // rapier's stages go in the tick, so it steps once per tick
+            .add_plugin(
+                RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0)
+                    .with_default_system_setup(false),
+            )
+            .add_plugin(shooting::ShootingPlugin)
+            .add_plugin(enemy::EnemyPlugin)
+            .add_plugin(health::HealthPlugin)
+            .add_plugin(damage::DamagePlugin)
+            .add_plugin(cartridge::CartridgePlugin)
+            .add_plugin(player::PlayerPlugin)
+            .add_plugin(weapon::WeaponPlugin)
+            .add_plugin(wave::WavePlugin)
+            .add_plugin(score::ScorePlugin)
+            .add_plugin(stats::StatsPlugin)
+            .add_plugin(level::LevelPlugin)
+            .add_plugin(wall::WallPlugin)
+            .add_plugin(replay::ReplayPlugin)
+            .add_plugin(snapshot::SnapshotPlugin)
+            .add_plugin(state::StatePlugin)
+            .add_tick_system_set(
+                SystemSet::on_enter(state::AppState::Playing).with_system(spawn_players),
+            )
+            //.add_startup_system(spawn_enemies)
+            .insert_resource(RapierConfiguration {
+                gravity: Vec2::ZERO,
+                timestep_mode: TimestepMode::Fixed {
+                    dt: tick::TICK.as_secs_f32(),
+                    substeps: 1,
+                },
+                ..default()
+            })
+            .init_resource::<input::PlayerInputs>()
+            .init_resource::<player::LocalPlayers>()
+            .add_tick_system_set(
+                SystemSet::on_update(state::AppState::Playing).with_system(player_movement),
+            )
+            .add_tick_system_to_stage(tick::TickStage::End, input::clear_presses);
+    }
 }
 
 // startup systems
@@ -53,106 +265,62 @@ fn setup(mut commands: Commands) {
     });
 }
 
-fn spawn_player(mut commands: Commands) {
+// one for each local player, the level moves them to its spawn
+fn spawn_players(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    local_players: Res<player::LocalPlayers>,
+) {
+    for slot in 0..local_players.0 {
+        spawn_player(&mut commands, &asset_server, slot);
+    }
+}
+
+// the server spawns one whenever someone joins
+pub fn spawn_player(commands: &mut Commands, asset_server: &AssetServer, slot: usize) -> Entity {
     commands
         .spawn_bundle(SpriteBundle {
             sprite: Sprite {
-                color: Color::BLUE,
+                color: player::player_color(slot),
                 custom_size: Some(Vec2::new(50., 50.)),
                 ..default()
             },
             transform: Transform::from_translation(Vec3::ZERO),
             ..default()
         })
-        .insert(Player)
+        .insert(Player(slot))
         .insert(Collider::cuboid(25.0, 25.0))
         .insert(RigidBody::Dynamic)
         .insert(LockedAxes::ROTATION_LOCKED)
-        .insert(shooting::Gun {
-            clip_size: 2,
-            shots_left: 2,
-            time_between_shots: 0.3,
-            reload_timer: Timer::from_seconds(2.0, true),
-            state: shooting::GunState::Ready,
-            damage: 1,
-            bullet_lifetime: 1.0,
-        })
+        // the defaults are the shotgun until the weapon file loads
+        .insert(shooting::Gun::from_def(&weapon::WeaponDef::default()))
+        .insert(weapon::Weapon(asset_server.load(weapon::STARTING_WEAPON)))
         .insert(shooting::Shotgun)
         .insert(shooting::ShotgunGauge::new(6))
-        .insert(health::Health::new(100));
-}
-
-fn spawn_bounds(mut commands: Commands) {
-    build_wall(
-        &mut commands,
-        Vec2::new(1920., 50.),
-        Vec3::new(0., 540., 0.),
-    );
-    build_wall(
-        &mut commands,
-        Vec2::new(1920., 50.),
-        Vec3::new(0., -540., 0.),
-    );
-    build_wall(
-        &mut commands,
-        Vec2::new(50., 1080.),
-        Vec3::new(960., 0., 0.),
-    );
-    build_wall(
-        &mut commands,
-        Vec2::new(50., 1080.),
-        Vec3::new(-960., 0., 0.),
-    );
-
-    // to put commands.inserts, etc in another fn:
-    // add &mut before the type in the parameters of the helper
-    // and &mut before the variable when calling it
-    // fn this(mut commands: Commands) like normal
-    // helper(&mut commands);                       &mut here
-    // fn helper(mut commands: &mut Commands)       &mut here
-}
-
-fn build_wall(mut commands: &mut Commands, size: Vec2, position: Vec3) {
-    commands
-        .spawn_bundle(SpriteBundle {
-            sprite: Sprite {
-                color: Color::BLACK,
-                custom_size: Some(size),
-                ..default()
-            },
-            transform: Transform::from_translation(position),
-            ..default()
+        .insert(cartridge::CartridgeInventory::new(3))
+        .insert(health::Health::new(100).with_overheal(50))
+        .insert(health::Regeneration::new(2.0, 5.0))
+        .insert(health::Shield::new(25, 10.0, 3.0))
+        .insert(damage::IFrames(1.0))
+        .insert(damage::CritChance {
+            chance: 0.1,
+            multiplier: 2.0,
         })
-        .insert(Collider::cuboid(size.x * 0.5, size.y * 0.5))
-        .insert(RigidBody::Fixed)
-        .insert(Wall);
+        .id()
 }
 
 // systems
 
 fn player_movement(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut q_player: Query<&mut Transform, With<Player>>,
-    time: Res<Time>,
+    player_inputs: Res<input::PlayerInputs>,
+    mut q_player: Query<(&Player, &mut Transform), Without<player::Dead>>,
+    time: Res<tick::GameTime>,
 ) {
-    let mut transform = q_player.single_mut();
-    let mut move_input = Vec2::ZERO;
-
-    if keyboard_input.pressed(KeyCode::A) {
-        move_input.x = -1.;
-    } else if keyboard_input.pressed(KeyCode::D) {
-        move_input.x = 1.;
+    for (player, mut transform) in q_player.iter_mut() {
+        let movement = player_inputs.get(player).movement;
+        transform.translation +=
+            movement.normalize_or_zero().extend(0.) * time.delta_seconds() * player::MOVE_SPEED;
     }
-
-    if keyboard_input.pressed(KeyCode::S) {
-        move_input.y = -1.;
-    } else if keyboard_input.pressed(KeyCode::W) {
-        move_input.y = 1.;
-    }
-
-    let move_speed = 350.;
-    transform.translation +=
-        move_input.normalize_or_zero().extend(0.) * time.delta_seconds() * move_speed;
 }
 
 fn update_mouse_position(
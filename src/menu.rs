@@ -0,0 +1,200 @@
+use bevy::{app::AppExit, prelude::*};
+use bevy_inspector_egui::{bevy_egui::EguiContext, egui};
+
+use crate::{
+    input::{Action, InputBindings, PlayerInputs, Rebinding},
+    level::{SelectedLevel, LEVEL_FILES},
+    player::{LocalPlayers, MAX_LOCAL_PLAYERS},
+    score::{HighScores, Score},
+    snapshot::{LastSnapshot, LoadSnapshot, SaveSnapshot, Snapshot},
+    state::{in_state, AppState},
+};
+
+// the screens around the fight. only added when there's a window
+pub struct MenuPlugin;
+
+impl Plugin for MenuPlugin {
+    fn build(&self, app: &mut App) {
+        // drawn every frame, not every tick, so they can't use SystemSet::on_update
+        app.add_system(main_menu.with_run_criteria(in_state(AppState::Menu)))
+            .add_system(pause_menu.with_run_criteria(in_state(AppState::Paused)))
+            .add_system(game_over_menu.with_run_criteria(in_state(AppState::GameOver)));
+    }
+}
+
+fn main_menu(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<AppState>>,
+    mut ev_exit: EventWriter<AppExit>,
+    high_scores: Res<HighScores>,
+    mut selected_level: ResMut<SelectedLevel>,
+    mut local_players: ResMut<LocalPlayers>,
+) {
+    menu_window("Capsule Shooter", egui_context.ctx_mut(), |ui| {
+        if ui.button("Play").clicked() {
+            let _ = state.set(AppState::Playing);
+        }
+
+        // levels/arena.level.ron shows as arena
+        ui.horizontal(|ui| {
+            ui.label("Level");
+            for path in LEVEL_FILES {
+                let name = path
+                    .rsplit('/')
+                    .next()
+                    .and_then(|file| file.split('.').next())
+                    .unwrap_or(path);
+                ui.selectable_value(&mut selected_level.0, path.to_string(), name);
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Players");
+            for players in 1..=MAX_LOCAL_PLAYERS {
+                ui.selectable_value(&mut local_players.0, players, players.to_string());
+            }
+        });
+        if local_players.0 > 1 {
+            ui.label("Player 1 uses the keyboard and mouse, everyone else a gamepad");
+        }
+
+        if ui.button("Quit").clicked() {
+            ev_exit.send(AppExit);
+        }
+
+        if !high_scores.scores.is_empty() {
+            ui.separator();
+            high_score_table(ui, &high_scores, None);
+        }
+    });
+}
+
+fn pause_menu(
+    mut commands: Commands,
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<AppState>>,
+    bindings: Res<InputBindings>,
+    mut rebinding: ResMut<Rebinding>,
+    last_snapshot: Option<Res<LastSnapshot>>,
+    mut selected_level: ResMut<SelectedLevel>,
+    mut local_players: ResMut<LocalPlayers>,
+    mut player_inputs: ResMut<PlayerInputs>,
+) {
+    menu_window("Paused", egui_context.ctx_mut(), |ui| {
+        // pressed like the pause key, so the replay records it too.
+        // otherwise playing it back stays paused forever
+        if ui.button("Resume").clicked() {
+            match player_inputs.0.first_mut() {
+                Some(input) => input.pause = true,
+                None => {
+                    let _ = state.pop();
+                }
+            }
+        }
+        // saved at the end of the next tick, which still runs while paused
+        if ui.button("Save snapshot").clicked() {
+            commands.insert_resource(SaveSnapshot);
+        }
+        if let Some(last_snapshot) = &last_snapshot {
+            // like restart, but the new fight is the snapshot
+            if ui.button("Load last snapshot").clicked() {
+                match Snapshot::load(&last_snapshot.0) {
+                    Ok(snapshot) => {
+                        snapshot.prepare(&mut selected_level, &mut local_players);
+                        commands.insert_resource(LoadSnapshot(snapshot));
+                        let _ = state.replace(AppState::Playing);
+                    }
+                    Err(err) => eprintln!("Couldn't read snapshot {:?}: {}", last_snapshot.0, err),
+                }
+            }
+        }
+        if ui.button("Quit to menu").clicked() {
+            let _ = state.replace(AppState::Menu);
+        }
+
+        ui.collapsing("Controls", |ui| {
+            egui::Grid::new("controls").show(ui, |ui| {
+                for action in Action::ALL {
+                    ui.label(format!("{:?}", action));
+                    if rebinding.0 == Some(action) {
+                        ui.label("press something...");
+                    } else {
+                        let bound = bindings
+                            .bindings(action)
+                            .iter()
+                            .map(|binding| format!("{:?}", binding))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        // input::capture_rebind picks up the next press
+                        if ui.button(bound).clicked() {
+                            rebinding.0 = Some(action);
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    });
+}
+
+fn game_over_menu(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<AppState>>,
+    score: Res<Score>,
+    high_scores: Res<HighScores>,
+) {
+    menu_window("Game over", egui_context.ctx_mut(), |ui| {
+        ui.label(format!("Score: {}", score.points));
+        ui.label(format!("Kills: {}", score.kills));
+        ui.label(format!("Perfect shots: {}", score.perfect_shots));
+        ui.label(format!("Best combo: {}", score.best_combo));
+        ui.label(format!("Time: {:.0}s", score.time));
+        if let Some(rank) = high_scores.last_rank {
+            ui.label(format!("New high score! #{}", rank + 1));
+        }
+        ui.separator();
+        high_score_table(ui, &high_scores, high_scores.last_rank);
+        ui.separator();
+
+        // replace takes Playing off the stack too
+        // so the old fight is torn down and a new one built
+        if ui.button("Restart").clicked() {
+            let _ = state.replace(AppState::Playing);
+        }
+        if ui.button("Main menu").clicked() {
+            let _ = state.replace(AppState::Menu);
+        }
+    });
+}
+
+// the run that just got in is highlighted
+fn high_score_table(ui: &mut egui::Ui, high_scores: &HighScores, highlight: Option<usize>) {
+    egui::Grid::new("high_scores").show(ui, |ui| {
+        ui.label("#");
+        ui.label("Score");
+        ui.label("Kills");
+        ui.label("Wave");
+        ui.end_row();
+
+        for (i, entry) in high_scores.scores.iter().enumerate() {
+            let color = if highlight == Some(i) {
+                egui::Color32::YELLOW
+            } else {
+                ui.visuals().text_color()
+            };
+            ui.colored_label(color, format!("{}", i + 1));
+            ui.colored_label(color, entry.points.to_string());
+            ui.colored_label(color, entry.kills.to_string());
+            ui.colored_label(color, entry.wave.to_string());
+            ui.end_row();
+        }
+    });
+}
+
+fn menu_window(title: &str, ctx: &egui::Context, add_contents: impl FnOnce(&mut egui::Ui)) {
+    egui::Window::new(title)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .collapsible(false)
+        .resizable(false)
+        .show(ctx, add_contents);
+}
@@ -0,0 +1,1213 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{app::AppExit, prelude::*};
+use bevy_inspector_egui::{bevy_egui::EguiContext, egui};
+use bevy_rapier2d::prelude::*;
+use rand::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    cartridge::{CartridgeInventory, CartridgeKind, CartridgePickup},
+    damage::DamageDealt,
+    enemy::{Enemy, EnemyKind},
+    headless::HeadlessApp,
+    health::{Dying, Health, HealthPickup, Shield, HEALTH_PICKUP_COLOR},
+    input::{self, PlayerInput, PlayerInputs},
+    level::{CurrentLevel, Level, LevelPlugin, Prop, SelectedLevel},
+    player::{self, Dead, LocalPlayers},
+    score::Score,
+    shooting::{self, Bullet, EnemyBullet, Gun, GunState, ImmediateReloadEvent, ShotgunGauge},
+    snapshot::SavedWall,
+    state::{despawn_with, AppState},
+    tick::{self, GameTime, RunSeed, TickApp, TickStage, TICKS_PER_SECOND},
+    wall::{Breakable, Door},
+    weapon::WeaponDef,
+    Player, Wall,
+};
+
+// online play. one machine runs the fight and everyone else sends it their input
+//
+// the server is the headless app with the whole GamePlugin, so shooting, enemies,
+// health and the rest only ever happen there. each tick it plays one input
+// from each client and sends them all everything that's in the arena.
+//
+// a client just draws what it was sent, except for its own player.
+// that moves and shoots as soon as you press (prediction), and when a state comes in
+// it's put where the server says and the inputs the server hasn't played yet
+// are gone through again on top (reconciliation).
+// predicted bullets are only for show, they go once the server has seen the shot
+// and its own bullets turn up instead.
+//
+// cargo run -- --server 7777 [--level path] [--seed n]   no window, waits for players
+// cargo run -- --connect 192.168.1.20:7777
+// cargo run -- --loopback [port]   a server on a thread and a window connected to it
+//
+// messages are json, one to a udp packet. nothing is resent,
+// the client sends its recent inputs again in every packet instead
+// and the server sends the whole state every tick
+
+pub const DEFAULT_PORT: u16 = 7777;
+
+// a state bigger than this is dropped, not split up
+const MAX_PACKET: usize = 60_000;
+
+// nothing heard for this long and they're gone
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAX_PLAYERS: usize = player::MAX_LOCAL_PLAYERS;
+
+// a client with a quicker clock gets ahead of the server.
+// past this many waiting, the oldest are thrown away
+const MAX_QUEUED_INPUTS: usize = 8;
+
+// how many of the newest inputs go in each packet
+const RESENT_INPUTS: usize = 16;
+
+// inputs kept for reconciling, if the server stops acking
+const MAX_PENDING_INPUTS: usize = TICKS_PER_SECOND as usize;
+
+// the rest aren't sent, to keep under MAX_PACKET
+const MAX_BULLETS: usize = 200;
+
+// on the game over screen before the next fight starts
+const RESTART_TICKS: u32 = 3 * TICKS_PER_SECOND;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// non blocking, everything polls it once a tick or frame
+pub fn bind(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn send<T: Serialize>(socket: &UdpSocket, addr: SocketAddr, message: &T) {
+    let bytes = match serde_json::to_vec(message) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Couldn't encode a message: {}", err);
+            return;
+        }
+    };
+    if bytes.len() > MAX_PACKET {
+        eprintln!("Not sending a {} byte message, it's too big", bytes.len());
+        return;
+    }
+    // a full buffer loses it, the same as the network would
+    let _ = socket.send_to(&bytes, addr);
+}
+
+// everything that's come in since last time
+fn receive<T: DeserializeOwned>(socket: &UdpSocket) -> Vec<(SocketAddr, T)> {
+    let mut buf = vec![0; 65_536];
+    let mut messages = Vec::new();
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, addr)) => match serde_json::from_slice(&buf[..len]) {
+                Ok(message) => messages.push((addr, message)),
+                Err(err) => eprintln!("Bad message from {}: {}", addr, err),
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            // windows says this on the next recv after sending to someone who's gone
+            Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
+            Err(err) => {
+                eprintln!("Couldn't receive: {}", err);
+                break;
+            }
+        }
+    }
+    messages
+}
+
+#[derive(Serialize, Deserialize)]
+enum ClientMessage {
+    Hello { version: String },
+    // by sequence number, oldest first. the newest few, acked or not
+    Inputs(Vec<(u32, PlayerInput)>),
+    Bye,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ServerMessage {
+    Welcome { slot: usize, level: String },
+    // full, or on a different version
+    Refused(String),
+    State(NetState),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    // nobody's playing yet
+    Waiting,
+    Playing,
+    GameOver,
+}
+
+// everything a client needs to draw the arena
+#[derive(Serialize, Deserialize, Clone)]
+struct NetState {
+    // counts up every tick, anything older than the last one is ignored
+    number: u64,
+    // the newest input from this client that's been played
+    ack: u32,
+    phase: Phase,
+    players: Vec<NetPlayer>,
+    enemies: Vec<NetEnemy>,
+    bullets: Vec<NetBullet>,
+    pickups: Vec<NetPickup>,
+    // breakable walls that are still up and doors
+    walls: Vec<SavedWall>,
+    score: Score,
+}
+
+// the hud needs all of it, but only for your own player
+#[derive(Serialize, Deserialize, Clone)]
+struct NetPlayer {
+    slot: usize,
+    position: Vec2,
+    dead: bool,
+    health: Health,
+    shield: Option<Shield>,
+    gun: Gun,
+    gauge: Option<ShotgunGauge>,
+    cartridges: CartridgeInventory,
+}
+
+// ids are the server's entities, so the client can tell which is which
+#[derive(Serialize, Deserialize, Clone)]
+struct NetEnemy {
+    id: u64,
+    kind: EnemyKind,
+    position: Vec2,
+    health: Health,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct NetBullet {
+    id: u64,
+    position: Vec2,
+    velocity: Vec2,
+    enemy: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct NetPickup {
+    id: u64,
+    position: Vec2,
+    kind: NetPickupKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+enum NetPickupKind {
+    Cartridge(CartridgeKind),
+    Health,
+}
+
+// server
+
+struct NetServer {
+    socket: UdpSocket,
+    clients: Vec<Client>,
+    // NetState::number
+    sent: u64,
+}
+
+struct Client {
+    addr: SocketAddr,
+    slot: usize,
+    last_heard: Instant,
+    // not played yet, by sequence number
+    queue: VecDeque<(u32, PlayerInput)>,
+    // the newest sequence number that's come in
+    received: u32,
+    // the newest one played
+    ack: u32,
+    last_input: PlayerInput,
+}
+
+struct NetServerPlugin;
+
+impl Plugin for NetServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_tick_system_to_stage(TickStage::Start, server_receive)
+            .add_tick_system_to_stage(TickStage::Start, server_phase.after(server_receive))
+            .add_tick_system_set(
+                SystemSet::on_update(AppState::Playing).with_system(server_players),
+            )
+            .add_tick_system_to_stage(TickStage::End, server_send);
+    }
+}
+
+// never returns. the fight waits in the menu until someone joins
+pub fn run_server(socket: UdpSocket, level: Option<String>, seed: Option<u64>) {
+    if let Ok(addr) = socket.local_addr() {
+        println!("Server listening on {}", addr);
+    }
+
+    let mut sim = HeadlessApp::empty(AppState::Menu);
+    if let Some(level) = level {
+        sim.app.insert_resource(SelectedLevel(level));
+    }
+    sim.app
+        .insert_resource(RunSeed(seed))
+        // the players come from the clients, see server_players
+        .insert_resource(LocalPlayers(0))
+        .insert_resource(NetServer {
+            socket,
+            clients: Vec::new(),
+            sent: 0,
+        })
+        .add_plugin(NetServerPlugin);
+
+    // the headless app would go as fast as it can, keep it to real time
+    let start = Instant::now();
+    let mut ticks = 0;
+    loop {
+        sim.update();
+        ticks += 1;
+        let due = start + tick::TICK * ticks;
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+}
+
+// a server on a thread on this machine, returns where to connect to it
+pub fn loopback(
+    port: u16,
+    level: Option<String>,
+    seed: Option<u64>,
+) -> std::io::Result<SocketAddr> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let socket = bind(addr)?;
+    thread::spawn(move || run_server(socket, level, seed));
+    Ok(addr)
+}
+
+fn server_receive(
+    mut server: ResMut<NetServer>,
+    selected: Res<SelectedLevel>,
+    mut player_inputs: ResMut<PlayerInputs>,
+) {
+    let server = &mut *server;
+    let now = Instant::now();
+
+    for (addr, message) in receive::<ClientMessage>(&server.socket) {
+        let known = server.clients.iter().position(|client| client.addr == addr);
+        match (message, known) {
+            (ClientMessage::Hello { version }, _) if version != VERSION => {
+                let reason = format!("the server is on version {}", VERSION);
+                send(&server.socket, addr, &ServerMessage::Refused(reason));
+            }
+            // the welcome got lost
+            (ClientMessage::Hello { .. }, Some(index)) => {
+                let welcome = ServerMessage::Welcome {
+                    slot: server.clients[index].slot,
+                    level: selected.0.clone(),
+                };
+                send(&server.socket, addr, &welcome);
+            }
+            (ClientMessage::Hello { .. }, None) => {
+                let free = (0..MAX_PLAYERS)
+                    .find(|slot| !server.clients.iter().any(|client| client.slot == *slot));
+                let slot = match free {
+                    Some(slot) => slot,
+                    None => {
+                        let reason = "the server is full".to_string();
+                        send(&server.socket, addr, &ServerMessage::Refused(reason));
+                        continue;
+                    }
+                };
+
+                println!("{} joined as player {}", addr, slot + 1);
+                server.clients.push(Client {
+                    addr,
+                    slot,
+                    last_heard: now,
+                    queue: VecDeque::new(),
+                    received: 0,
+                    ack: 0,
+                    last_input: PlayerInput::default(),
+                });
+                let welcome = ServerMessage::Welcome {
+                    slot,
+                    level: selected.0.clone(),
+                };
+                send(&server.socket, addr, &welcome);
+            }
+            (ClientMessage::Inputs(inputs), Some(index)) => {
+                let client = &mut server.clients[index];
+                client.last_heard = now;
+                for (seq, input) in inputs {
+                    if seq > client.received {
+                        client.received = seq;
+                        client.queue.push_back((seq, input));
+                    }
+                }
+                while client.queue.len() > MAX_QUEUED_INPUTS {
+                    client.queue.pop_front();
+                }
+            }
+            (ClientMessage::Bye, Some(index)) => {
+                println!("{} left", addr);
+                server.clients.remove(index);
+            }
+            // hasn't said hello, or already left
+            (_, None) => {}
+        }
+    }
+
+    server.clients.retain(|client| {
+        let alive = now.duration_since(client.last_heard) < TIMEOUT;
+        if !alive {
+            println!("{} timed out", client.addr);
+        }
+        alive
+    });
+
+    // one input a tick each, in the order they were sent
+    player_inputs.resize(MAX_PLAYERS);
+    for input in player_inputs.0.iter_mut() {
+        *input = PlayerInput::default();
+    }
+    for client in server.clients.iter_mut() {
+        let input = match client.queue.pop_front() {
+            Some((seq, input)) => {
+                client.ack = seq;
+                input
+            }
+            // nothing's come in yet, they're probably still holding the same keys
+            None => PlayerInput {
+                switch_weapon: None,
+                reload: false,
+                swap_cartridge: false,
+                ..client.last_input
+            },
+        };
+        client.last_input = input;
+        // one player can't pause everyone
+        player_inputs.0[client.slot] = PlayerInput {
+            pause: false,
+            ..input
+        };
+    }
+}
+
+// the fight starts when someone joins, starts again a while after everyone's died
+// and goes back to waiting when everyone's left
+fn server_phase(
+    server: Res<NetServer>,
+    mut state: ResMut<State<AppState>>,
+    q_players: Query<Option<&Dead>, With<Player>>,
+    mut game_over_ticks: Local<u32>,
+) {
+    let current = state.current().clone();
+    if current != AppState::GameOver {
+        *game_over_ticks = 0;
+    }
+
+    // errors if a change is already queued this tick, that's fine
+    let _ = match current {
+        AppState::Menu if !server.clients.is_empty() => state.set(AppState::Playing),
+        AppState::Menu => Ok(()),
+        _ if server.clients.is_empty() => state.replace(AppState::Menu),
+        AppState::GameOver => {
+            *game_over_ticks += 1;
+            if *game_over_ticks >= RESTART_TICKS {
+                state.replace(AppState::Playing)
+            } else {
+                Ok(())
+            }
+        }
+        // the last one alive left, player_death never sees them die
+        AppState::Playing
+            if !q_players.is_empty() && q_players.iter().all(|dead| dead.is_some()) =>
+        {
+            state.push(AppState::GameOver)
+        }
+        _ => Ok(()),
+    };
+}
+
+// a player for everyone connected, and none for anyone who's left
+fn server_players(
+    mut commands: Commands,
+    server: Res<NetServer>,
+    asset_server: Res<AssetServer>,
+    current: Option<Res<CurrentLevel>>,
+    levels: Res<Assets<Level>>,
+    q_players: Query<(Entity, &Player)>,
+) {
+    for (entity, player) in q_players.iter() {
+        if !server.clients.iter().any(|client| client.slot == player.0) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+
+    let level = current.as_ref().and_then(|current| current.get(&levels));
+    for client in server.clients.iter() {
+        if q_players.iter().any(|(_, player)| player.0 == client.slot) {
+            continue;
+        }
+        let player = crate::spawn_player(&mut commands, &asset_server, client.slot);
+        // joining before the level is built, build_level moves them
+        if let Some(level) = level {
+            let position = level.spawn_for(&Player(client.slot));
+            commands
+                .entity(player)
+                .insert(Transform::from_translation(position.extend(0.)));
+        }
+    }
+}
+
+fn server_send(
+    mut server: ResMut<NetServer>,
+    state: Res<State<AppState>>,
+    score: Res<Score>,
+    q_players: Query<(
+        &Player,
+        &Transform,
+        Option<&Dead>,
+        &Health,
+        Option<&Shield>,
+        &Gun,
+        Option<&ShotgunGauge>,
+        &CartridgeInventory,
+    )>,
+    q_enemies: Query<(Entity, &Enemy, &Transform, &Health), Without<Dying>>,
+    q_bullets: Query<(Entity, &Transform, &Velocity, Option<&EnemyBullet>), With<Bullet>>,
+    q_cart_pickups: Query<(Entity, &Transform, &CartridgePickup)>,
+    q_health_pickups: Query<(Entity, &Transform), With<HealthPickup>>,
+    q_walls: Query<
+        (&Transform, Option<&Health>, Option<&Door>),
+        (
+            With<Wall>,
+            Or<(With<Breakable>, With<Door>)>,
+            Without<Dying>,
+        ),
+    >,
+) {
+    let server = &mut *server;
+    if server.clients.is_empty() {
+        return;
+    }
+    server.sent += 1;
+
+    let phase = match state.current() {
+        AppState::Menu => Phase::Waiting,
+        AppState::GameOver => Phase::GameOver,
+        _ => Phase::Playing,
+    };
+
+    let players = q_players
+        .iter()
+        .map(
+            |(player, transform, dead, health, shield, gun, gauge, cartridges)| NetPlayer {
+                slot: player.0,
+                position: transform.translation.truncate(),
+                dead: dead.is_some(),
+                health: health.clone(),
+                shield: shield.cloned(),
+                gun: gun.clone(),
+                gauge: gauge.cloned(),
+                cartridges: cartridges.clone(),
+            },
+        )
+        .collect();
+    let enemies = q_enemies
+        .iter()
+        .map(|(entity, enemy, transform, health)| NetEnemy {
+            id: entity.to_bits(),
+            kind: enemy.0,
+            position: transform.translation.truncate(),
+            health: health.clone(),
+        })
+        .collect();
+    let bullets = q_bullets
+        .iter()
+        .take(MAX_BULLETS)
+        .map(|(entity, transform, velocity, enemy)| NetBullet {
+            id: entity.to_bits(),
+            position: transform.translation.truncate(),
+            velocity: velocity.linvel,
+            enemy: enemy.is_some(),
+        })
+        .collect();
+    let carts = q_cart_pickups
+        .iter()
+        .map(|(entity, transform, pickup)| NetPickup {
+            id: entity.to_bits(),
+            position: transform.translation.truncate(),
+            kind: NetPickupKind::Cartridge(pickup.kind),
+        });
+    let health = q_health_pickups
+        .iter()
+        .map(|(entity, transform)| NetPickup {
+            id: entity.to_bits(),
+            position: transform.translation.truncate(),
+            kind: NetPickupKind::Health,
+        });
+    let walls = q_walls
+        .iter()
+        .map(|(transform, health, door)| SavedWall {
+            position: transform.translation.truncate(),
+            health: health.cloned(),
+            door: door.copied(),
+        })
+        .collect();
+
+    let mut net_state = NetState {
+        number: server.sent,
+        ack: 0,
+        phase,
+        players,
+        enemies,
+        bullets,
+        pickups: carts.chain(health).collect(),
+        walls,
+        score: score.clone(),
+    };
+    for client in server.clients.iter() {
+        net_state.ack = client.ack;
+        send(
+            &server.socket,
+            client.addr,
+            &ServerMessage::State(net_state.clone()),
+        );
+    }
+}
+
+// client
+
+pub struct NetClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    // ours on the server, once it's said welcome
+    slot: Option<usize>,
+    // of the last input sent
+    seq: u32,
+    // sent but not played on the server yet, oldest first
+    pending: VecDeque<(u32, PlayerInput)>,
+    last_heard: Instant,
+    last_hello: Option<Instant>,
+    // the newest state, applied at the start of the next tick
+    latest: Option<NetState>,
+    // NetState::number of the newest one so far
+    newest: u64,
+    phase: Phase,
+}
+
+impl NetClient {
+    pub fn connect(server: SocketAddr) -> std::io::Result<Self> {
+        let local = match server.ip() {
+            ip if ip.is_loopback() => SocketAddr::new(ip, 0),
+            IpAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            IpAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+
+        Ok(NetClient {
+            socket: bind(local)?,
+            server,
+            slot: None,
+            seq: 0,
+            pending: VecDeque::new(),
+            last_heard: Instant::now(),
+            last_hello: None,
+            latest: None,
+            newest: 0,
+            phase: Phase::Waiting,
+        })
+    }
+}
+
+// what main adds instead of GamePlugin and the menu when it's connecting to a server.
+// there's no gameplay in it, just the level, the tick and the inputs going out
+pub struct NetClientPlugin;
+
+impl Plugin for NetClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(tick::TickPlugin)
+            // the tick runs rapier's stages. only our own player is simulated by it,
+            // so the prediction stops at the walls like the server does
+            .add_plugin(
+                RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0)
+                    .with_default_system_setup(false),
+            )
+            .insert_resource(RapierConfiguration {
+                gravity: Vec2::ZERO,
+                timestep_mode: TimestepMode::Fixed {
+                    dt: tick::TICK.as_secs_f32(),
+                    substeps: 1,
+                },
+                ..default()
+            })
+            .add_plugin(LevelPlugin)
+            .add_asset::<WeaponDef>()
+            .init_resource::<PlayerInputs>()
+            .init_resource::<LocalPlayers>()
+            .init_resource::<Score>()
+            .init_resource::<Replicas>()
+            // the hud listens for these, they just never come
+            .add_tick_event::<DamageDealt>()
+            .add_tick_event::<ImmediateReloadEvent>()
+            .add_tick_state(AppState::Menu)
+            .add_system(client_receive)
+            .add_system(connection_status)
+            .add_system_to_stage(CoreStage::Last, say_bye)
+            .add_tick_system_to_stage(TickStage::Start, apply_state)
+            .add_tick_system_set(
+                SystemSet::on_enter(AppState::Playing).with_system(spawn_own_player),
+            )
+            .add_tick_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(crate::player_movement)
+                    .with_system(predict_shots)
+                    .with_system(move_predicted_bullets),
+            )
+            .add_tick_system_set(
+                SystemSet::on_exit(AppState::Playing)
+                    .with_system(despawn_with::<Player>)
+                    .with_system(despawn_with::<Wall>)
+                    .with_system(despawn_with::<Prop>)
+                    .with_system(despawn_with::<Replica>)
+                    .with_system(despawn_with::<PredictedBullet>)
+                    .with_system(clear_replicas),
+            )
+            .add_tick_system_to_stage(TickStage::End, client_send.before(input::clear_presses))
+            .add_tick_system_to_stage(TickStage::End, input::clear_presses);
+    }
+}
+
+// a copy of something on the server, only drawn
+#[derive(Component)]
+struct Replica;
+
+// server ids to replicas
+#[derive(Default)]
+struct Replicas {
+    // other players, by slot
+    players: HashMap<usize, Entity>,
+    // enemies, bullets and pickups
+    things: HashMap<u64, Entity>,
+}
+
+// on our own player
+#[derive(Component)]
+struct Predicted {
+    // our GameTime, not the server's
+    last_shot: f32,
+    // sequence numbers of inputs that fired a shot the server hasn't played yet
+    shots: Vec<u32>,
+}
+
+// doesn't hit anything, it just flies until the server's bullet turns up
+#[derive(Component)]
+struct PredictedBullet {
+    seq: u32,
+    velocity: Vec2,
+    lifetime: Timer,
+}
+
+// every frame, so nothing piles up in the socket while there's no tick
+fn client_receive(
+    mut client: ResMut<NetClient>,
+    mut selected: ResMut<SelectedLevel>,
+    mut ev_exit: EventWriter<AppExit>,
+) {
+    let client = &mut *client;
+    let now = Instant::now();
+
+    if client.slot.is_none()
+        && client
+            .last_hello
+            .map_or(true, |last| now - last >= HELLO_INTERVAL)
+    {
+        // a slow start shouldn't count against the server
+        if client.last_hello.is_none() {
+            client.last_heard = now;
+        }
+        client.last_hello = Some(now);
+        let hello = ClientMessage::Hello {
+            version: VERSION.to_string(),
+        };
+        send(&client.socket, client.server, &hello);
+    }
+
+    for (addr, message) in receive::<ServerMessage>(&client.socket) {
+        if addr != client.server {
+            continue;
+        }
+        client.last_heard = now;
+
+        match message {
+            ServerMessage::Welcome { slot, level } => {
+                if client.slot.is_none() {
+                    println!("Joined {} as player {}", addr, slot + 1);
+                }
+                client.slot = Some(slot);
+                selected.0 = level;
+            }
+            ServerMessage::Refused(reason) => {
+                eprintln!("Couldn't join {}: {}", addr, reason);
+                ev_exit.send(AppExit);
+            }
+            ServerMessage::State(net_state) => {
+                if client.slot.is_some() && net_state.number > client.newest {
+                    client.newest = net_state.number;
+                    client.latest = Some(net_state);
+                }
+            }
+        }
+    }
+
+    if now.duration_since(client.last_heard) > TIMEOUT {
+        eprintln!("Lost the connection to {}", client.server);
+        ev_exit.send(AppExit);
+    }
+}
+
+// it's player 0 here, the only local one, whatever slot it has on the server.
+// the rest of it comes in the first state
+fn spawn_own_player(mut commands: Commands, client: Res<NetClient>) {
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: player::player_color(client.slot.unwrap_or(0)),
+                custom_size: Some(Vec2::new(50., 50.)),
+                ..default()
+            },
+            ..default()
+        })
+        .insert(Player(0))
+        // the same body as on the server, or we'd walk through walls until the next state
+        .insert(Collider::cuboid(25.0, 25.0))
+        .insert(RigidBody::Dynamic)
+        .insert(LockedAxes::ROTATION_LOCKED)
+        .insert(Predicted {
+            last_shot: f32::NEG_INFINITY,
+            shots: Vec::new(),
+        });
+}
+
+// moves the player's box by step until it hits a wall or prop.
+// one axis at a time so it slides along the wall instead of sticking to it
+fn slide(rapier_context: &RapierContext, from: Vec2, step: Vec2) -> Vec2 {
+    // a pixel smaller, rapier leaves the player just touching the wall
+    // and casting from there would stop every move along it
+    let shape = Collider::cuboid(24.0, 24.0);
+    let mut position = from;
+    for step in [Vec2::new(step.x, 0.), Vec2::new(0., step.y)] {
+        if step == Vec2::ZERO {
+            continue;
+        }
+        let toi = rapier_context
+            .cast_shape(position, 0., step, &shape, 1., QueryFilter::only_fixed())
+            .map_or(1., |(_, toi)| toi.toi);
+        position += step * toi;
+    }
+    position
+}
+
+// the newest state replaces everything but our own predicted bits
+fn apply_state(
+    mut commands: Commands,
+    mut client: ResMut<NetClient>,
+    mut replicas: ResMut<Replicas>,
+    mut state: ResMut<State<AppState>>,
+    mut score: ResMut<Score>,
+    mut q_own: Query<(Entity, &mut Transform, &mut Sprite, &mut Predicted), Without<Wall>>,
+    mut q_replicas: Query<
+        (&mut Transform, &mut Sprite),
+        (With<Replica>, Without<Predicted>, Without<Wall>),
+    >,
+    mut q_walls: Query<
+        (
+            Entity,
+            &Transform,
+            Option<&Breakable>,
+            Option<&mut Door>,
+            &mut Sprite,
+        ),
+        With<Wall>,
+    >,
+    q_predicted_bullets: Query<(Entity, &PredictedBullet)>,
+    rapier_context: Res<RapierContext>,
+) {
+    let client = &mut *client;
+    let net_state = match client.latest.take() {
+        Some(net_state) => net_state,
+        None => return,
+    };
+    let slot = match client.slot {
+        Some(slot) => slot,
+        None => return,
+    };
+
+    let ack = net_state.ack;
+    while matches!(client.pending.front(), Some((seq, _)) if *seq <= ack) {
+        client.pending.pop_front();
+    }
+
+    // follow the server from one fight to the next
+    client.phase = net_state.phase;
+    let current = state.current().clone();
+    let _ = match (net_state.phase, &current) {
+        (Phase::Playing, AppState::Menu) => state.set(AppState::Playing),
+        (Phase::Playing, AppState::GameOver) => state.replace(AppState::Playing),
+        (Phase::GameOver, AppState::Playing) => state.push(AppState::GameOver),
+        (Phase::Waiting, AppState::Playing | AppState::GameOver) => state.replace(AppState::Menu),
+        _ => Ok(()),
+    };
+    if current != AppState::Playing {
+        return;
+    }
+
+    if let (Some(own), Ok((entity, mut transform, mut sprite, mut predicted))) = (
+        net_state.players.iter().find(|player| player.slot == slot),
+        q_own.get_single_mut(),
+    ) {
+        // where the server has us, plus the moves it hasn't played yet.
+        // those stop at the walls too, a few of them add up to more than a wall is thick
+        let mut position = own.position;
+        if !own.dead {
+            for (_, input) in client.pending.iter() {
+                let step = input.movement.normalize_or_zero()
+                    * player::MOVE_SPEED
+                    * tick::TICK.as_secs_f32();
+                position = slide(&rapier_context, position, step);
+            }
+        }
+        transform.translation = position.extend(transform.translation.z);
+
+        // and the shells it doesn't know we've fired
+        predicted.shots.retain(|seq| *seq > ack);
+        let mut gun = own.gun.clone();
+        gun.shots_left = gun.shots_left.saturating_sub(predicted.shots.len() as u32);
+
+        let mut own_commands = commands.entity(entity);
+        own_commands
+            .insert(gun)
+            .insert(own.health.clone())
+            .insert(own.cartridges.clone());
+        match &own.shield {
+            Some(shield) => own_commands.insert(shield.clone()),
+            None => own_commands.remove::<Shield>(),
+        };
+        match &own.gauge {
+            Some(gauge) => own_commands.insert(gauge.clone()),
+            None => own_commands.remove::<ShotgunGauge>(),
+        };
+        if own.dead {
+            own_commands.insert(Dead);
+            sprite.color = Color::GRAY;
+        } else {
+            own_commands.remove::<Dead>();
+            sprite.color = player::player_color(slot);
+        }
+    }
+
+    for (entity, bullet) in q_predicted_bullets.iter() {
+        if bullet.seq <= ack {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    let mut players = HashMap::new();
+    for other in net_state
+        .players
+        .iter()
+        .filter(|player| player.slot != slot)
+    {
+        let color = if other.dead {
+            Color::GRAY
+        } else {
+            player::player_color(other.slot)
+        };
+        let entity = replica(
+            &mut commands,
+            &mut q_replicas,
+            replicas.players.get(&other.slot).copied(),
+            Transform::from_translation(other.position.extend(0.)),
+            Sprite {
+                color,
+                custom_size: Some(Vec2::new(50., 50.)),
+                ..default()
+            },
+        );
+        commands.entity(entity).insert(other.health.clone());
+        players.insert(other.slot, entity);
+    }
+
+    let mut things = HashMap::new();
+    for enemy in net_state.enemies.iter() {
+        let (color, size) = enemy.kind.look();
+        let entity = replica(
+            &mut commands,
+            &mut q_replicas,
+            replicas.things.get(&enemy.id).copied(),
+            Transform::from_translation(enemy.position.extend(0.)),
+            Sprite {
+                color,
+                custom_size: Some(Vec2::new(size, size)),
+                ..default()
+            },
+        );
+        commands.entity(entity).insert(enemy.health.clone());
+        things.insert(enemy.id, entity);
+    }
+    for bullet in net_state.bullets.iter() {
+        let transform = Transform {
+            translation: bullet.position.extend(0.),
+            rotation: Quat::from_rotation_arc_2d(Vec2::Y, bullet.velocity.normalize_or_zero()),
+            ..default()
+        };
+        let entity = replica(
+            &mut commands,
+            &mut q_replicas,
+            replicas.things.get(&bullet.id).copied(),
+            transform,
+            shooting::bullet_sprite(bullet.enemy),
+        );
+        things.insert(bullet.id, entity);
+    }
+    for pickup in net_state.pickups.iter() {
+        let color = match pickup.kind {
+            NetPickupKind::Cartridge(kind) => kind.color(),
+            NetPickupKind::Health => HEALTH_PICKUP_COLOR,
+        };
+        let entity = replica(
+            &mut commands,
+            &mut q_replicas,
+            replicas.things.get(&pickup.id).copied(),
+            Transform::from_translation(pickup.position.extend(0.)),
+            Sprite {
+                color,
+                custom_size: Some(Vec2::new(15., 15.)),
+                ..default()
+            },
+        );
+        things.insert(pickup.id, entity);
+    }
+
+    // gone on the server
+    for (slot, entity) in replicas.players.iter() {
+        if !players.contains_key(slot) {
+            commands.entity(*entity).despawn_recursive();
+        }
+    }
+    for (id, entity) in replicas.things.iter() {
+        if !things.contains_key(id) {
+            commands.entity(*entity).despawn_recursive();
+        }
+    }
+    replicas.players = players;
+    replicas.things = things;
+
+    // the level builds the same walls here, they're matched up by where they are
+    for (entity, transform, breakable, door, mut sprite) in q_walls.iter_mut() {
+        if breakable.is_none() && door.is_none() {
+            continue;
+        }
+        let position = transform.translation.truncate();
+        let saved = match net_state
+            .walls
+            .iter()
+            .find(|wall| wall.position.distance(position) < 0.5)
+        {
+            Some(saved) => saved,
+            None => {
+                // broken on the server
+                if breakable.is_some() {
+                    commands.entity(entity).despawn_recursive();
+                }
+                continue;
+            }
+        };
+
+        if let Some(health) = &saved.health {
+            commands.entity(entity).insert(health.clone());
+        }
+        if let (Some(mut door), Some(saved_door)) = (door, saved.door) {
+            if door.open != saved_door.open {
+                door.set_open(saved_door.open, &mut commands.entity(entity), &mut sprite);
+            }
+        }
+    }
+
+    *score = net_state.score;
+}
+
+// moves the replica that's already there, or spawns one
+fn replica(
+    commands: &mut Commands,
+    q_replicas: &mut Query<
+        (&mut Transform, &mut Sprite),
+        (With<Replica>, Without<Predicted>, Without<Wall>),
+    >,
+    existing: Option<Entity>,
+    transform: Transform,
+    sprite: Sprite,
+) -> Entity {
+    if let Some(entity) = existing {
+        if let Ok((mut current, mut current_sprite)) = q_replicas.get_mut(entity) {
+            *current = transform;
+            *current_sprite = sprite;
+            return entity;
+        }
+    }
+
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite,
+            transform,
+            ..default()
+        })
+        .insert(Replica)
+        .id()
+}
+
+fn clear_replicas(mut replicas: ResMut<Replicas>) {
+    *replicas = Replicas::default();
+}
+
+// the same checks shoot_bullet makes, so the gun fires here when it will there.
+// the real bullets come a round trip later
+fn predict_shots(
+    mut commands: Commands,
+    client: Res<NetClient>,
+    player_inputs: Res<PlayerInputs>,
+    mut q_own: Query<
+        (
+            &Transform,
+            &mut Gun,
+            &mut Predicted,
+            Option<&CartridgeInventory>,
+        ),
+        Without<Dead>,
+    >,
+    time: Res<GameTime>,
+) {
+    let input = player_inputs.get(&Player(0));
+    if !input.fire {
+        return;
+    }
+
+    for (transform, mut gun, mut predicted, carts) in q_own.iter_mut() {
+        let now = time.seconds() as f32;
+        let mut time_since_last_shot = now - predicted.last_shot;
+        if let Some(cart) = carts.and_then(|carts| carts.loaded()) {
+            time_since_last_shot = cart.time_since_last_shot(time_since_last_shot);
+        }
+        if gun.shoot(time_since_last_shot).is_err() {
+            continue;
+        }
+
+        predicted.last_shot = now;
+        // client_send gives this tick's input the next number
+        let seq = client.seq + 1;
+        predicted.shots.push(seq);
+
+        let dir = (input.aim - transform.translation.truncate()).normalize_or_zero();
+        // the spread won't come out the same as the server's, close enough
+        let angles = if gun.pellets > 1 {
+            gun.spread_pattern
+                .angles(gun.pellets, gun.spread, &mut thread_rng())
+        } else {
+            vec![0.]
+        };
+        for angle in angles {
+            let pellet_dir = (Quat::from_rotation_z(angle) * dir.extend(0.)).truncate();
+            commands
+                .spawn_bundle(SpriteBundle {
+                    sprite: shooting::bullet_sprite(false),
+                    transform: Transform {
+                        translation: transform.translation,
+                        rotation: Quat::from_rotation_arc_2d(Vec2::Y, pellet_dir),
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(PredictedBullet {
+                    seq,
+                    velocity: pellet_dir * gun.projectile_speed,
+                    lifetime: Timer::from_seconds(gun.bullet_lifetime, false),
+                });
+        }
+
+        gun.state = GunState::Ready;
+        gun.shots_left -= 1;
+        if gun.shots_left == 0 {
+            gun.start_reload();
+        }
+    }
+}
+
+fn move_predicted_bullets(
+    mut commands: Commands,
+    mut q_bullets: Query<(Entity, &mut Transform, &mut PredictedBullet)>,
+    time: Res<GameTime>,
+) {
+    for (entity, mut transform, mut bullet) in q_bullets.iter_mut() {
+        transform.translation += (bullet.velocity * time.delta_seconds()).extend(0.);
+        if bullet.lifetime.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+// this tick's input, and the ones before it in case those got lost
+fn client_send(mut client: ResMut<NetClient>, player_inputs: Res<PlayerInputs>) {
+    let client = &mut *client;
+    if client.slot.is_none() {
+        return;
+    }
+
+    client.seq += 1;
+    let input = PlayerInput {
+        pause: false,
+        ..player_inputs.get(&Player(0))
+    };
+    client.pending.push_back((client.seq, input));
+    while client.pending.len() > MAX_PENDING_INPUTS {
+        client.pending.pop_front();
+    }
+
+    let start = client.pending.len().saturating_sub(RESENT_INPUTS);
+    let inputs = client.pending.iter().skip(start).copied().collect();
+    send(
+        &client.socket,
+        client.server,
+        &ClientMessage::Inputs(inputs),
+    );
+}
+
+// so the server doesn't wait out the timeout
+fn say_bye(client: Res<NetClient>, mut ev_exit: EventReader<AppExit>) {
+    if ev_exit.iter().count() > 0 && client.slot.is_some() {
+        send(&client.socket, client.server, &ClientMessage::Bye);
+    }
+}
+
+fn connection_status(mut egui_context: ResMut<EguiContext>, client: Res<NetClient>) {
+    let text = match (client.slot, client.phase) {
+        (None, _) => format!("Connecting to {}...", client.server),
+        (Some(_), Phase::Waiting) => "Waiting for the server...".to_string(),
+        (Some(_), Phase::GameOver) => "Game over, the next fight starts soon".to_string(),
+        (Some(_), Phase::Playing) => return,
+    };
+
+    egui::Area::new("connection_status")
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0., 0.))
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.heading(text);
+        });
+}
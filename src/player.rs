@@ -0,0 +1,119 @@
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+
+use crate::{
+    damage::{self, DamageEvent, DamageKind, Invulnerable},
+    health::{self, DeathEvent, EntityKind},
+    state::AppState,
+    tick::TickApp,
+    Player,
+};
+
+pub struct PlayerPlugin;
+
+impl Plugin for PlayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_tick_event::<GameOverEvent>().add_tick_system_set(
+            SystemSet::on_update(AppState::Playing)
+                .with_system(contact_damage.before(damage::apply_damage))
+                .with_system(player_death.after(health::death))
+                .with_system(game_over_event),
+        );
+    }
+}
+
+// how many people are playing on this machine, picked in the main menu.
+// each gets their own Player(slot) with its own gun, gauge and cartridges
+pub struct LocalPlayers(pub usize);
+
+impl Default for LocalPlayers {
+    fn default() -> Self {
+        LocalPlayers(1)
+    }
+}
+
+// pixels a second
+pub const MOVE_SPEED: f32 = 350.;
+
+// one keyboard and three gamepads
+pub const MAX_LOCAL_PLAYERS: usize = 4;
+
+pub fn player_color(slot: usize) -> Color {
+    match slot % MAX_LOCAL_PLAYERS {
+        0 => Color::BLUE,
+        1 => Color::ORANGE,
+        2 => Color::GREEN,
+        _ => Color::PURPLE,
+    }
+}
+
+// enemies with this hurt the player by touching them
+#[derive(Component)]
+pub struct ContactDamage(pub u32);
+
+// sent once every player is dead
+pub struct GameOverEvent;
+
+// a player ran out of health
+// they stay in the world so nothing has to deal with them suddenly being gone
+// but they can't move or shoot and enemies ignore them
+#[derive(Component)]
+pub struct Dead;
+
+fn contact_damage(
+    rapier_context: Res<RapierContext>,
+    q_player: Query<Entity, (With<Player>, Without<Dead>)>,
+    q_enemies: Query<(Entity, &ContactDamage, &Transform)>,
+    mut ev_damage: EventWriter<DamageEvent>,
+) {
+    // sent every frame while touching, the player's IFrames stop it stacking up
+    for player in q_player.iter() {
+        for (enemy, damage, transform) in q_enemies.iter() {
+            if let Some(contact) = rapier_context.contact_pair(player, enemy) {
+                if contact.has_any_active_contacts() {
+                    ev_damage.send(DamageEvent {
+                        target: player,
+                        source: Some(enemy),
+                        amount: damage.0,
+                        kind: DamageKind::Contact,
+                        position: transform.translation.truncate(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+// health::death has queued Dead for them, it lands at the end of the stage
+// so anyone who died this tick still shows up in q_alive
+pub fn player_death(
+    mut commands: Commands,
+    mut ev_death: EventReader<DeathEvent>,
+    mut q_player: Query<&mut Sprite, With<Player>>,
+    q_alive: Query<Entity, (With<Player>, Without<Dead>)>,
+    mut ev_game_over: EventWriter<GameOverEvent>,
+) {
+    let mut died = Vec::new();
+    for ev in ev_death.iter() {
+        if ev.kind != EntityKind::Player {
+            continue;
+        }
+
+        if let Ok(mut sprite) = q_player.get_mut(ev.entity) {
+            sprite.color = Color::GRAY;
+        }
+        commands.entity(ev.entity).remove::<Invulnerable>();
+        died.push(ev.entity);
+    }
+
+    // in co-op the fight goes on while anyone is still standing
+    if !died.is_empty() && q_alive.iter().all(|player| died.contains(&player)) {
+        ev_game_over.send(GameOverEvent);
+    }
+}
+
+fn game_over_event(mut ev_game_over: EventReader<GameOverEvent>) {
+    for _ in ev_game_over.iter() {
+        println!("Game over");
+    }
+}
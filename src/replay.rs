@@ -0,0 +1,382 @@
+use std::{
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    enemy::Enemy,
+    health::Health,
+    input::{self, PlayerInputs},
+    level::SelectedLevel,
+    player::LocalPlayers,
+    score::Score,
+    shooting::Bullet,
+    state::AppState,
+    tick::{self, GameRng, GameTime, RunSeed, TickApp, TickStage},
+    Player,
+};
+
+// every run is recorded: the seed, the level and everyone's PlayerInput every tick.
+// with the same inputs on the same ticks the run plays out the same again,
+// so a replay file is all it takes to see what a tester saw.
+// cargo run -- --replay file watches it, add --headless to just check it
+//
+// every HASH_INTERVAL ticks a hash of the fight is saved too,
+// and playback complains on the first one that doesn't match.
+// that happens if the game changed since the recording,
+// or if something left over from an earlier run changed how this one went
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_tick_system_set(
+            SystemSet::on_enter(AppState::Playing)
+                .with_system(start_recording.after(tick::start_run)),
+        )
+        .add_tick_system_set(SystemSet::on_exit(AppState::Playing).with_system(save_recording))
+        .add_tick_system_to_stage(TickStage::Start, play_input)
+        .add_tick_system_to_stage(TickStage::End, record_tick.before(input::clear_presses))
+        .add_tick_system_to_stage(TickStage::End, check_hash);
+    }
+}
+
+const HASH_INTERVAL: u64 = 60;
+
+// where to write recordings, one file per run
+pub struct ReplayExport {
+    pub dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Replay {
+    // the game version it was recorded on
+    pub version: String,
+    pub ticks_per_second: u32,
+    pub seed: u64,
+    pub level: String,
+    // how many local players there were
+    pub players: usize,
+    // (ticks, inputs), the same inputs held for that many ticks in a row
+    pub inputs: Vec<(u32, PlayerInputs)>,
+    // one for tick 0, HASH_INTERVAL, 2 * HASH_INTERVAL...
+    pub hashes: Vec<u64>,
+}
+
+impl Replay {
+    fn new(seed: u64, level: String, players: usize) -> Self {
+        Replay {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            ticks_per_second: tick::TICKS_PER_SECOND,
+            seed,
+            level,
+            players,
+            inputs: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, inputs: &PlayerInputs) {
+        match self.inputs.last_mut() {
+            Some((ticks, last)) if last == inputs => *ticks += 1,
+            _ => self.inputs.push((1, inputs.clone())),
+        }
+    }
+
+    pub fn ticks(&self) -> u32 {
+        self.inputs.iter().map(|(ticks, _)| ticks).sum()
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        ron::from_str(&text).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+    }
+
+    // returns the file it wrote
+    pub fn save(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or(0);
+        let path = dir.join(format!("replay-{}.ron", millis));
+
+        // not pretty, these get long
+        let text = ron::to_string(self)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        fs::write(&path, text)?;
+        Ok(path)
+    }
+}
+
+// the run being recorded
+pub struct ReplayRecorder(pub Replay);
+
+// feeds the replay in instead of the player, until it runs out
+pub struct ReplayPlayback {
+    replay: Replay,
+    // where we are in replay.inputs
+    step: usize,
+    ticks_into_step: u32,
+    // the first tick that didn't match
+    pub desync: Option<u64>,
+}
+
+impl ReplayPlayback {
+    pub fn finished(&self) -> bool {
+        self.step >= self.replay.inputs.len()
+    }
+
+    fn next_input(&mut self) -> Option<PlayerInputs> {
+        let (ticks, inputs) = self.replay.inputs.get(self.step)?.clone();
+        self.ticks_into_step += 1;
+        if self.ticks_into_step >= ticks {
+            self.step += 1;
+            self.ticks_into_step = 0;
+        }
+        Some(inputs)
+    }
+}
+
+// set up an app to play a replay from its first tick,
+// it has to start in AppState::Playing
+pub fn play(app: &mut App, replay: Replay) {
+    if replay.version != env!("CARGO_PKG_VERSION")
+        || replay.ticks_per_second != tick::TICKS_PER_SECOND
+    {
+        eprintln!(
+            "Replay was recorded on version {} at {} ticks a second, it probably won't match",
+            replay.version, replay.ticks_per_second
+        );
+    }
+
+    app.insert_resource(RunSeed(Some(replay.seed)))
+        .insert_resource(SelectedLevel(replay.level.clone()))
+        .insert_resource(LocalPlayers(replay.players))
+        .insert_resource(ReplayPlayback {
+            replay,
+            step: 0,
+            ticks_into_step: 0,
+            desync: None,
+        });
+}
+
+fn start_recording(
+    mut commands: Commands,
+    rng: Res<GameRng>,
+    level: Res<SelectedLevel>,
+    local_players: Res<LocalPlayers>,
+    playback: Option<Res<ReplayPlayback>>,
+) {
+    if playback.map(|playback| !playback.finished()) == Some(true) {
+        return;
+    }
+    commands.insert_resource(ReplayRecorder(Replay::new(
+        rng.seed(),
+        level.0.clone(),
+        local_players.0,
+    )));
+}
+
+fn save_recording(
+    mut commands: Commands,
+    recorder: Option<Res<ReplayRecorder>>,
+    export: Option<Res<ReplayExport>>,
+) {
+    let recorder = match recorder {
+        Some(recorder) => recorder,
+        None => return,
+    };
+    commands.remove_resource::<ReplayRecorder>();
+
+    if let Some(export) = export {
+        match recorder.0.save(&export.dir) {
+            Ok(path) => println!("Replay written to {:?}", path),
+            Err(err) => eprintln!("Couldn't write replay: {}", err),
+        }
+    }
+}
+
+// FNV-1a. std's DefaultHasher can change between rust versions
+// and replay files stick around longer than the build that wrote them
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    // the same bytes on every machine, whatever its endianness or pointer size
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+type Fighters<'w, 's> = Query<
+    'w,
+    's,
+    (&'static Transform, Option<&'static Health>),
+    Or<(With<Player>, With<Enemy>, With<Bullet>)>,
+>;
+
+// a hash of everything that moves or can be hurt, plus the score and the rng
+// so two runs that have gone different ways almost certainly hash differently
+fn state_hash(game_time: &GameTime, rng: &GameRng, score: &Score, q_fighters: &Fighters) -> u64 {
+    // query order depends on what was spawned before, so sort first
+    let mut fighters: Vec<u64> = q_fighters
+        .iter()
+        .map(|(transform, health)| {
+            let mut hasher = StableHasher::new();
+            transform.translation.x.to_bits().hash(&mut hasher);
+            transform.translation.y.to_bits().hash(&mut hasher);
+            transform.rotation.z.to_bits().hash(&mut hasher);
+            health.map(|health| health.current()).hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect();
+    fighters.sort_unstable();
+
+    let mut hasher = StableHasher::new();
+    game_time.tick().hash(&mut hasher);
+    rng.peek().hash(&mut hasher);
+    score.points.hash(&mut hasher);
+    fighters.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn record_tick(
+    recorder: Option<ResMut<ReplayRecorder>>,
+    player_inputs: Res<PlayerInputs>,
+    game_time: Res<GameTime>,
+    rng: Res<GameRng>,
+    score: Res<Score>,
+    q_fighters: Fighters,
+) {
+    let mut recorder = match recorder {
+        Some(recorder) => recorder,
+        None => return,
+    };
+
+    recorder.0.push(&player_inputs);
+    if game_time.tick() % HASH_INTERVAL == 0 {
+        let hash = state_hash(&game_time, &rng, &score, &q_fighters);
+        recorder.0.hashes.push(hash);
+    }
+}
+
+fn play_input(playback: Option<ResMut<ReplayPlayback>>, mut player_inputs: ResMut<PlayerInputs>) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+
+    // once it runs out the player has control again
+    if let Some(inputs) = playback.next_input() {
+        *player_inputs = inputs;
+        if playback.finished() {
+            println!("Replay finished");
+        }
+    }
+}
+
+fn check_hash(
+    playback: Option<ResMut<ReplayPlayback>>,
+    game_time: Res<GameTime>,
+    rng: Res<GameRng>,
+    score: Res<Score>,
+    q_fighters: Fighters,
+) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    if playback.desync.is_some() || game_time.tick() % HASH_INTERVAL != 0 {
+        return;
+    }
+
+    let index = (game_time.tick() / HASH_INTERVAL) as usize;
+    let expected = match playback.replay.hashes.get(index) {
+        Some(hash) => *hash,
+        None => return,
+    };
+
+    if state_hash(&game_time, &rng, &score, &q_fighters) != expected {
+        eprintln!("Replay desynced at tick {}", game_time.tick());
+        playback.desync = Some(game_time.tick());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{demo_script, HeadlessApp};
+
+    fn record(ticks: u64) -> Replay {
+        let mut sim = HeadlessApp::new(demo_script());
+        sim.app.insert_resource(RunSeed(Some(5)));
+        sim.run_ticks(ticks);
+        sim.world().resource::<ReplayRecorder>().0.clone()
+    }
+
+    fn play_back(replay: Replay) -> Option<u64> {
+        let ticks = replay.ticks() as u64;
+        let mut sim = HeadlessApp::replay(replay);
+        sim.run_ticks(ticks);
+        let playback = sim.world().resource::<ReplayPlayback>();
+        assert!(playback.finished());
+        playback.desync
+    }
+
+    #[test]
+    fn playing_a_recording_matches_it() {
+        let replay = record(600);
+        assert!(
+            replay.hashes.len() >= 10,
+            "only {} hashes",
+            replay.hashes.len()
+        );
+        assert_eq!(play_back(replay), None);
+    }
+
+    #[test]
+    fn playback_notices_a_different_run() {
+        let mut replay = record(300);
+        replay.hashes[2] ^= 1;
+        assert_eq!(play_back(replay), Some(2 * HASH_INTERVAL));
+    }
+
+    #[test]
+    fn hashes_dont_change_between_builds() {
+        // the published FNV-1a test vectors
+        let mut hasher = StableHasher::new();
+        assert_eq!(hasher.finish(), 0xcbf2_9ce4_8422_2325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+}
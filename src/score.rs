@@ -0,0 +1,247 @@
+use std::{fs, path::PathBuf};
+
+use bevy::prelude::*;
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    enemy::EnemyKind,
+    health::{self, DeathEvent, EntityKind},
+    player::{self, GameOverEvent},
+    shooting::ImmediateReloadEvent,
+    state::AppState,
+    tick::{GameTime, TickApp},
+    wave::WaveDirector,
+    Player,
+};
+
+pub struct ScorePlugin;
+
+impl Plugin for ScorePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Score>()
+            .add_tick_system_set(SystemSet::on_enter(AppState::Playing).with_system(reset_score))
+            .add_tick_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(score_kills.after(health::death))
+                    .with_system(score_perfect_shots)
+                    .with_system(decay_combo)
+                    .with_system(end_of_run.after(player::player_death)),
+            );
+    }
+}
+
+// each kill adds this much to the multiplier
+const COMBO_STEP: f32 = 0.1;
+const MAX_MULTIPLIER: f32 = 4.0;
+// seconds without a kill before the combo starts dropping
+const COMBO_TIME: f32 = 3.0;
+const PERFECT_SHOT_POINTS: u32 = 50;
+
+// the current run
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Score {
+    pub points: u64,
+    pub kills: u32,
+    pub perfect_shots: u32,
+    // kills in a row without a break
+    pub combo: u32,
+    pub best_combo: u32,
+    pub time: f32,
+    #[serde(with = "crate::snapshot::timer")]
+    combo_timer: Timer,
+}
+
+impl Default for Score {
+    fn default() -> Self {
+        Score {
+            points: 0,
+            kills: 0,
+            perfect_shots: 0,
+            combo: 0,
+            best_combo: 0,
+            time: 0.,
+            combo_timer: Timer::from_seconds(COMBO_TIME, true),
+        }
+    }
+}
+
+impl Score {
+    pub fn multiplier(&self) -> f32 {
+        (1. + self.combo as f32 * COMBO_STEP).min(MAX_MULTIPLIER)
+    }
+
+    fn add(&mut self, points: u32) {
+        self.points += (points as f32 * self.multiplier()).round() as u64;
+    }
+}
+
+fn enemy_points(kind: EnemyKind) -> u32 {
+    match kind {
+        EnemyKind::Walker => 100,
+        EnemyKind::Charger => 150,
+        EnemyKind::Shooter => 150,
+        EnemyKind::Tank => 300,
+        EnemyKind::Splitter => 200,
+        EnemyKind::Splitling => 25,
+    }
+}
+
+fn reset_score(mut score: ResMut<Score>) {
+    *score = Score::default();
+}
+
+fn score_kills(
+    mut ev_death: EventReader<DeathEvent>,
+    q_player: Query<(), With<Player>>,
+    mut score: ResMut<Score>,
+) {
+    for ev in ev_death.iter() {
+        let kind = match ev.kind {
+            EntityKind::Enemy(kind) => kind,
+            _ => continue,
+        };
+        // enemies can kill each other with explosions etc, that doesn't count
+        let by_player = ev
+            .killer
+            .map(|killer| q_player.contains(killer))
+            .unwrap_or(false);
+        if !by_player {
+            continue;
+        }
+
+        score.kills += 1;
+        score.combo += 1;
+        score.best_combo = score.best_combo.max(score.combo);
+        score.combo_timer.reset();
+        score.add(enemy_points(kind));
+    }
+}
+
+// the shotgun gauge sends this when a shot is perfect
+fn score_perfect_shots(mut ev_reload: EventReader<ImmediateReloadEvent>, mut score: ResMut<Score>) {
+    for _ in ev_reload.iter() {
+        score.perfect_shots += 1;
+        score.add(PERFECT_SHOT_POINTS);
+    }
+}
+
+// lose a step of combo every COMBO_TIME seconds without a kill
+fn decay_combo(mut score: ResMut<Score>, time: Res<GameTime>) {
+    score.time += time.delta_seconds();
+    if score.combo > 0 && score.combo_timer.tick(time.delta()).just_finished() {
+        score.combo -= 1;
+    }
+}
+
+// the high score table is only there in the windowed game
+// so headless runs don't fill it up
+fn end_of_run(
+    mut ev_game_over: EventReader<GameOverEvent>,
+    score: Res<Score>,
+    director: Res<WaveDirector>,
+    high_scores: Option<ResMut<HighScores>>,
+) {
+    // the run is over when the last player dies
+    if ev_game_over.iter().count() == 0 {
+        return;
+    }
+
+    let entry = HighScore {
+        points: score.points,
+        kills: score.kills,
+        wave: director.wave,
+        time: score.time,
+    };
+
+    println!(
+        "Run over: {} points, {} kills, {} perfect shots, best combo {}, wave {}, {:.0}s",
+        score.points, score.kills, score.perfect_shots, score.best_combo, entry.wave, score.time
+    );
+
+    if let Some(mut high_scores) = high_scores {
+        if let Some(rank) = high_scores.insert(entry) {
+            println!("New high score! #{}", rank + 1);
+            if let Err(err) = high_scores.save() {
+                eprintln!("Couldn't save high scores: {}", err);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HighScore {
+    pub points: u64,
+    pub kills: u32,
+    pub wave: u32,
+    // seconds
+    pub time: f32,
+}
+
+const MAX_HIGH_SCORES: usize = 10;
+
+// saved as highscores.ron in the user's data folder
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HighScores {
+    pub scores: Vec<HighScore>,
+    // where in the table the last run went, if it got in
+    #[serde(skip)]
+    pub last_rank: Option<usize>,
+}
+
+impl HighScores {
+    fn path() -> Option<PathBuf> {
+        Some(
+            dirs::data_dir()?
+                .join("capsule_shooter")
+                .join("highscores.ron"),
+        )
+    }
+
+    pub fn load() -> Self {
+        let path = match HighScores::path() {
+            Some(path) => path,
+            None => return HighScores::default(),
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(text) => ron::from_str(&text).unwrap_or_else(|err| {
+                eprintln!("Couldn't read {:?}, starting a new table: {}", path, err);
+                HighScores::default()
+            }),
+            Err(_) => HighScores::default(),
+        }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = match HighScores::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let text = ron::ser::to_string_pretty(self, PrettyConfig::default())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        fs::write(path, text)
+    }
+
+    // Some(place in the table) if it was good enough to get in
+    pub fn insert(&mut self, entry: HighScore) -> Option<usize> {
+        let rank = self
+            .scores
+            .iter()
+            .position(|score| entry.points > score.points)
+            .unwrap_or(self.scores.len());
+
+        self.last_rank = if rank < MAX_HIGH_SCORES {
+            self.scores.insert(rank, entry);
+            self.scores.truncate(MAX_HIGH_SCORES);
+            Some(rank)
+        } else {
+            None
+        };
+        self.last_rank
+    }
+}
@@ -1,41 +1,64 @@
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{cartridge::Cartridge, enemy::Enemy, health::Health, MouseWorldPos, Player, Wall};
+use crate::{
+    cartridge::{CartridgeInventory, Explosive, Piercing, Ricochet},
+    damage::{self, DamageEvent, DamageKind},
+    enemy::Enemy,
+    health::Dying,
+    input::PlayerInputs,
+    player::Dead,
+    state::AppState,
+    tick::{GameRng, GameTime, TickApp},
+    wall::{Breakable, Door, OneWay, Reflective},
+    weapon::{PerfectShotRule, ReloadStyle, SpreadPattern, WeaponDef},
+    Player, Wall,
+};
 
 pub struct ShootingPlugin;
 
 impl Plugin for ShootingPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<BulletHitEvent>()
-            .add_event::<ShotgunBulletEndEvent>()
-            .add_event::<ImmediateReloadEvent>()
-            .add_system(shoot_bullet)
-            .add_system(reload)
-            .add_system(immediate_reload)
-            .add_system(move_bullet)
-            .add_system(bullet_lifetime)
-            .add_system(bullet_collision_rapier)
-            .add_system(bullet_event)
-            .add_system(shotgun_event)
-            .add_system(shotgun_check_shots)
-            .add_system(shotgun_check_gauge);
+        app.add_tick_event::<BulletHitEvent>()
+            .add_tick_event::<ShotgunBulletEndEvent>()
+            .add_tick_event::<ImmediateReloadEvent>()
+            .add_tick_event::<ShootErrorEvent>()
+            .add_tick_event::<ShotFiredEvent>()
+            .add_tick_event::<BulletEndEvent>()
+            .add_tick_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(manual_reload)
+                    .with_system(shoot_bullet.after(manual_reload))
+                    .with_system(reload.after(shoot_bullet))
+                    .with_system(immediate_reload)
+                    .with_system(bullet_lifetime)
+                    .with_system(bullet_collision_rapier.before(damage::apply_damage))
+                    .with_system(shotgun_event)
+                    .with_system(shotgun_check_shots)
+                    .with_system(shotgun_check_gauge),
+            );
     }
 }
 
-#[derive(Component)]
-struct Bullet {
-    dir: Vec2,
+// moved by rapier, see projectile_body
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct Bullet {
+    #[serde(with = "crate::snapshot::timer")]
     lifetime: Timer,
     damage: u32,
+    // who fired it, gets the credit for kills
+    // a snapshot saves who it was separately, entities change when it's loaded
+    #[serde(skip)]
+    pub owner: Option<Entity>,
 }
 
 impl Bullet {
-    pub fn new(dir: Vec2, lifetime: f32, damage: u32) -> Self {
+    pub fn new(lifetime: f32, damage: u32, owner: Option<Entity>) -> Self {
         Self {
-            dir,
             lifetime: Timer::from_seconds(lifetime, false),
             damage,
+            owner,
         }
     }
 
@@ -59,223 +82,518 @@ impl Bullet {
     }
 }
 
+// where a bullet hit an enemy or a wall, for sparks and sounds.
+// the damage numbers come from damage::DamageDealt instead,
+// that has what was left after armor and crits. nothing listens to this yet
+#[allow(dead_code)]
 pub struct BulletHitEvent {
-    pos: Vec2,
+    pub pos: Vec2,
+    // the enemy or wall
+    pub target: Entity,
 }
 
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize, Clone)]
 pub struct Gun {
+    // from the weapon file
+    pub name: String,
     pub clip_size: u32,
     pub shots_left: u32,
     pub time_between_shots: f32,
+    #[serde(with = "crate::snapshot::timer")]
     pub reload_timer: Timer,
+    pub reload_style: ReloadStyle,
     pub state: GunState,
+    // seconds since startup
+    pub last_shot: f32,
     pub damage: u32,
     pub bullet_lifetime: f32,
+    pub projectile_speed: f32,
+    pub pellets: u32,
+    // degrees
+    pub spread: f32,
+    pub spread_pattern: SpreadPattern,
+    pub perfect_shot: PerfectShotRule,
 }
 
 impl Gun {
-    fn shoot(self, time_since_last_shot: f32) -> Result<(), ShootError> {
-        if self.shots_left <= 0 {
-            return Err(ShootError::OutOfAmmo);
+    pub fn from_def(def: &WeaponDef) -> Self {
+        Gun {
+            name: def.name.clone(),
+            clip_size: def.clip_size,
+            shots_left: def.clip_size,
+            time_between_shots: def.time_between_shots,
+            reload_timer: Timer::from_seconds(def.reload_time, true),
+            reload_style: def.reload_style,
+            state: GunState::Ready,
+            last_shot: f32::NEG_INFINITY,
+            damage: def.damage,
+            bullet_lifetime: def.bullet_lifetime,
+            projectile_speed: def.projectile_speed,
+            pellets: def.pellets,
+            spread: def.spread,
+            spread_pattern: def.spread_pattern,
+            perfect_shot: def.perfect_shot,
         }
-        if self.state == GunState::Reloading {
+    }
+
+    // change the stats without refilling the clip
+    // used when the weapon file is edited while the game is running
+    pub fn apply_def(&mut self, def: &WeaponDef) {
+        self.name = def.name.clone();
+        self.clip_size = def.clip_size;
+        self.shots_left = self.shots_left.min(def.clip_size);
+        self.time_between_shots = def.time_between_shots;
+        self.reload_timer
+            .set_duration(std::time::Duration::from_secs_f32(def.reload_time));
+        self.reload_style = def.reload_style;
+        self.damage = def.damage;
+        self.bullet_lifetime = def.bullet_lifetime;
+        self.projectile_speed = def.projectile_speed;
+        self.pellets = def.pellets;
+        self.spread = def.spread;
+        self.spread_pattern = def.spread_pattern;
+        self.perfect_shot = def.perfect_shot;
+    }
+
+    // the one place that decides if the gun can fire right now
+    pub fn shoot(&self, time_since_last_shot: f32) -> Result<(), ShootError> {
+        // shell by shell can fire whatever is loaded so far.
+        // before the ammo, an empty gun that's already reloading says so
+        if self.state == GunState::Reloading && self.reload_style == ReloadStyle::FullClip {
             return Err(ShootError::Reloading);
         }
+        if self.shots_left == 0 {
+            return Err(ShootError::OutOfAmmo);
+        }
         if self.time_between_shots > time_since_last_shot {
             return Err(ShootError::ShotCooldown);
         }
 
         Ok(())
     }
+
+    pub fn start_reload(&mut self) {
+        if self.state == GunState::Reloading || self.shots_left >= self.clip_size {
+            return;
+        }
+        self.state = GunState::Reloading;
+        self.reload_timer.reset();
+    }
 }
 
-enum ShootError {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShootError {
+    // pulled the trigger on an empty gun
     OutOfAmmo,
     Reloading,
     ShotCooldown,
 }
 
+// sent when the trigger is pulled or held but the gun can't fire,
+// once for each reason until it's let go. for clicks, ui flashes, etc
+pub struct ShootErrorEvent {
+    pub shooter: Entity,
+    pub error: ShootError,
+}
+
 // Ready when you have bullets and aren't waiting for time between shots
 // switch to reloading when you run out of ammo
 // shooting when you click and while waiting for time between shots?
 // is this necessary?
 // shooting might also be useful for slowing you or restricting your aim?
-#[derive(PartialEq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum GunState {
     Ready,
     Reloading,
     Shooting,
 }
 
+// whose gauge it goes on is the bullet's owner
 struct ShotgunBulletEndEvent {
-    side: BulletSide,
+    shooter: Option<Entity>,
+    pellet: u32,
     shot_number: u32,
     reason: BulletEndReason,
 }
 
-enum BulletEndReason {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BulletEndReason {
     HitEnemy,
     HitWall,
     Expired,
 }
 
-struct ImmediateReloadEvent;
+// every time a player fires, however many pellets
+pub struct ShotFiredEvent {
+    pub shooter: Entity,
+    pub weapon: String,
+    pub bullets: u32,
+}
+
+// every player bullet sends one of these when it's gone
+// HitEnemy if it hit anything on the way, even if it ended on a wall
+pub struct BulletEndEvent {
+    pub shooter: Option<Entity>,
+    pub weapon: String,
+    pub reason: BulletEndReason,
+}
+
+// on the player's bullets, for stats
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct ShotFrom {
+    weapon: String,
+    // a piercing bullet that already hit something
+    hit: bool,
+}
+
+impl ShotFrom {
+    fn end(&self, shooter: Option<Entity>, reason: BulletEndReason) -> BulletEndEvent {
+        BulletEndEvent {
+            shooter,
+            weapon: self.weapon.clone(),
+            reason: if self.hit {
+                BulletEndReason::HitEnemy
+            } else {
+                reason
+            },
+        }
+    }
+}
+
+// a perfect shot refilled the shooter's clip
+pub struct ImmediateReloadEvent {
+    pub shooter: Entity,
+}
 
 #[derive(Component)]
 pub struct Shotgun;
 
-#[derive(Component)]
-struct ShotgunBullet {
-    side: BulletSide,
+#[derive(Component, Serialize, Deserialize, Clone)]
+pub struct ShotgunBullet {
+    pellet: u32,
     shot_number: u32,
 }
 
-#[derive(Debug, Clone, Copy)]
-enum BulletSide {
-    Left,
-    Right,
-}
-
 // component struct ShotgunGauge
-// vec![num_bullets] of HitPair
-// struct HitPair
-// left: Option<bool>
-// right: Option<bool>
-// get_both_hit -> Option<bool>
+// vec![num_bullets] of ShotResult
+// struct ShotResult
+// pellets: vec![num_pellets] of Option<bool>
 // none if still waiting
 // query<Gauge>
 // eventReader
 // when event happens, add to gauge
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize, Clone)]
 pub struct ShotgunGauge {
-    hit_pairs: Vec<HitPair>,
+    shots: Vec<ShotResult>,
 }
 
 impl ShotgunGauge {
     pub fn new(size: usize) -> Self {
         let mut gauge = ShotgunGauge {
-            hit_pairs: Vec::with_capacity(size),
+            shots: Vec::with_capacity(size),
         };
         for _ in 0..size {
-            gauge.hit_pairs.push(HitPair::new());
+            gauge.shots.push(ShotResult::new(0));
         }
         gauge
     }
+
+    // the gauge is indexed by shot number
+    // so it needs at least one slot per bullet in the clip
+    pub fn fit_clip(&mut self, clip_size: usize) {
+        while self.shots.len() < clip_size {
+            self.shots.push(ShotResult::new(0));
+        }
+    }
+
+    // indexed by shot number, for the hud
+    pub fn shots(&self) -> &[ShotResult] {
+        &self.shots
+    }
 }
 
-struct HitPair {
-    left: Option<bool>,
-    right: Option<bool>,
+// what happened to each pellet of one shot
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ShotResult {
+    pellets: Vec<Option<bool>>,
+    // already checked for a perfect shot
+    // kept around until the next shot so the hud can show it
+    checked: bool,
 }
 
-impl HitPair {
-    fn new() -> Self {
-        HitPair {
-            left: None,
-            right: None,
+impl ShotResult {
+    fn new(pellets: u32) -> Self {
+        ShotResult {
+            pellets: vec![None; pellets as usize],
+            checked: false,
         }
     }
+
+    // Some(true) hit, Some(false) missed, None still flying
+    pub fn pellets(&self) -> &[Option<bool>] {
+        &self.pellets
+    }
+
+    // None while any pellet is still flying
+    fn hits(&self) -> Option<u32> {
+        if self.pellets.is_empty() {
+            return None;
+        }
+
+        let mut hits = 0;
+        for pellet in self.pellets.iter() {
+            match pellet {
+                Some(true) => hits += 1,
+                Some(false) => (),
+                None => return None,
+            }
+        }
+        Some(hits)
+    }
 }
 
 fn shoot_bullet(
     mut commands: Commands,
-    mouse_input: Res<Input<MouseButton>>,
+    player_inputs: Res<PlayerInputs>,
     mut q_player: Query<
         (
+            Entity,
+            &Player,
             &Transform,
             &mut Gun,
             Option<&Shotgun>,
             Option<&mut ShotgunGauge>,
-            Option<&mut Cartridge>,
+            Option<&mut CartridgeInventory>,
         ),
-        With<Player>,
+        Without<Dead>,
     >,
-    mouse_pos: Res<MouseWorldPos>,
-    time: Res<Time>,
-    mut time_of_next_shot: Local<f32>,
+    time: Res<GameTime>,
+    mut rng: ResMut<GameRng>,
+    mut ev_shoot_error: EventWriter<ShootErrorEvent>,
+    mut ev_shot_fired: EventWriter<ShotFiredEvent>,
+    // by player slot, why the trigger was last refused while it's held
+    mut refused: Local<Vec<Option<ShootError>>>,
 ) {
-    let (transform, mut gun, shotgun, gauge, cart) = q_player.single_mut();
+    for (player, slot, transform, mut gun, shotgun, gauge, mut carts) in q_player.iter_mut() {
+        let player_input = player_inputs.get(slot);
+        if refused.len() <= slot.0 {
+            refused.resize(slot.0 + 1, None);
+        }
+
+        if !player_input.fire {
+            refused[slot.0] = None;
+            continue;
+        }
+
+        let cart = carts.as_ref().and_then(|carts| carts.loaded()).copied();
+
+        let now = time.seconds() as f32;
+        let mut time_since_last_shot = now - gun.last_shot;
+        if let Some(cart) = cart {
+            time_since_last_shot = cart.time_since_last_shot(time_since_last_shot);
+        }
 
-    let shot_timer_ok = time.time_since_startup().as_secs_f32() > *time_of_next_shot;
-    let has_shots = gun.shots_left > 0;
-    let button_pressed = mouse_input.pressed(MouseButton::Left);
+        if let Err(error) = gun.shoot(time_since_last_shot) {
+            // once per reason while it's held, not every tick
+            if refused[slot.0] != Some(error) {
+                ev_shoot_error.send(ShootErrorEvent {
+                    shooter: player,
+                    error,
+                });
+            }
+            refused[slot.0] = Some(error);
+            continue;
+        }
+        refused[slot.0] = None;
 
-    if shot_timer_ok && has_shots && button_pressed {
-        *time_of_next_shot = time.time_since_startup().as_secs_f32() + gun.time_between_shots;
+        gun.last_shot = now;
+        // firing stops a shell by shell reload with what's loaded so far
+        gun.state = GunState::Ready;
 
         let dir = Vec2::new(
-            mouse_pos.0.x - transform.translation.x,
-            mouse_pos.0.y - transform.translation.y,
+            player_input.aim.x - transform.translation.x,
+            player_input.aim.y - transform.translation.y,
         )
         .normalize_or_zero();
 
-        // do more damage if you have a cart attached
-        let damage = if let Some(_) = cart { 2 } else { gun.damage };
+        // boosted when it's fired, the cartridge might be gone by the time it lands
+        let damage = match cart {
+            Some(cart) => cart.damage(gun.damage),
+            None => gun.damage,
+        };
 
         if let Some(_shotgun) = shotgun {
             // shoot like a shotgun
-            // degress to radians
-            // pi / 180 = 0.0174533
-            let left_dir = Quat::mul_vec3(Quat::from_rotation_z(6. * 0.0174533), dir.extend(0.0));
-            let right_dir = Quat::mul_vec3(Quat::from_rotation_z(-6. * 0.0174533), dir.extend(0.0));
+            let shot_number = gun.clip_size - gun.shots_left;
 
             // reset the tracking on this shot number
             if let Some(mut gauge) = gauge {
-                gauge.hit_pairs[(gun.clip_size - gun.shots_left) as usize] = HitPair {
-                    left: None,
-                    right: None,
-                };
+                if let Some(shot) = gauge.shots.get_mut(shot_number as usize) {
+                    *shot = ShotResult::new(gun.pellets);
+                }
             }
 
-            spawn_shotgun_bullet(
-                &mut commands,
-                transform.translation.clone(),
-                left_dir.truncate(),
-                gun.bullet_lifetime,
-                damage,
-                ShotgunBullet {
-                    side: BulletSide::Left,
-                    shot_number: gun.clip_size - gun.shots_left,
-                },
-            );
-            spawn_shotgun_bullet(
-                &mut commands,
-                transform.translation.clone(),
-                right_dir.truncate(),
-                gun.bullet_lifetime,
-                damage,
-                ShotgunBullet {
-                    side: BulletSide::Right,
-                    shot_number: gun.clip_size - gun.shots_left,
-                },
-            );
+            let angles = gun
+                .spread_pattern
+                .angles(gun.pellets, gun.spread, &mut *rng);
+            for (pellet, angle) in angles.into_iter().enumerate() {
+                let pellet_dir = Quat::mul_vec3(Quat::from_rotation_z(angle), dir.extend(0.0));
+
+                let bullet = spawn_shotgun_bullet(
+                    &mut commands,
+                    transform.translation.clone(),
+                    pellet_dir.truncate(),
+                    gun.projectile_speed,
+                    gun.bullet_lifetime,
+                    damage,
+                    Some(player),
+                    ShotgunBullet {
+                        pellet: pellet as u32,
+                        shot_number,
+                    },
+                );
+                if let Some(cart) = cart {
+                    cart.insert_bullet_effect(&mut commands.entity(bullet));
+                }
+                commands.entity(bullet).insert(ShotFrom {
+                    weapon: gun.name.clone(),
+                    hit: false,
+                });
+            }
         } else {
-            spawn_bullet(
+            let bullet = spawn_bullet(
                 &mut commands,
                 transform.translation.clone(),
                 dir,
+                gun.projectile_speed,
                 gun.bullet_lifetime,
                 damage,
+                Some(player),
             );
+            if let Some(cart) = cart {
+                cart.insert_bullet_effect(&mut commands.entity(bullet));
+            }
+            commands.entity(bullet).insert(ShotFrom {
+                weapon: gun.name.clone(),
+                hit: false,
+            });
+        }
+
+        ev_shot_fired.send(ShotFiredEvent {
+            shooter: player,
+            weapon: gun.name.clone(),
+            bullets: if shotgun.is_some() { gun.pellets } else { 1 },
+        });
+
+        // one shot of power, however many pellets
+        if let Some(carts) = carts.as_mut() {
+            carts.use_power();
         }
 
         gun.shots_left -= 1;
         if gun.shots_left <= 0 {
-            gun.state = GunState::Reloading;
-            //gun.reload_timer = Timer::from_seconds(duration, repeating)
+            gun.start_reload();
+        }
+    }
+}
+
+// blue for the player's, red for enemies'
+pub fn bullet_sprite(enemy: bool) -> Sprite {
+    if enemy {
+        Sprite {
+            color: Color::rgb(0.75, 0.25, 0.25),
+            custom_size: Some(Vec2::new(10., 10.)),
+            ..default()
+        }
+    } else {
+        Sprite {
+            color: Color::rgb(0.25, 0.25, 0.75),
+            custom_size: Some(Vec2::new(10., 20.)),
+            ..default()
         }
     }
 }
 
-fn spawn_bullet(commands: &mut Commands, pos: Vec3, dir: Vec2, lifetime: f32, damage: u32) {
+fn spawn_bullet(
+    commands: &mut Commands,
+    pos: Vec3,
+    dir: Vec2,
+    speed: f32,
+    lifetime: f32,
+    damage: u32,
+    owner: Option<Entity>,
+) -> Entity {
     commands
         .spawn_bundle(SpriteBundle {
-            sprite: Sprite {
-                color: Color::rgb(0.25, 0.25, 0.75),
-                custom_size: Some(Vec2::new(10., 20.)),
+            sprite: bullet_sprite(false),
+            transform: Transform {
+                translation: pos,
+                rotation: Quat::from_rotation_arc_2d(Vec2::Y, dir),
                 ..default()
             },
+            ..default()
+        })
+        .insert(Bullet::new(lifetime, damage, owner))
+        .insert_bundle(projectile_body(dir, speed))
+        .id()
+}
+
+// a bullet from a snapshot, carrying on with however long it had left
+pub fn respawn_bullet(
+    commands: &mut Commands,
+    pos: Vec3,
+    velocity: Vec2,
+    bullet: Bullet,
+    enemy: bool,
+) -> Entity {
+    let dir = velocity.normalize_or_zero();
+    let entity = if enemy {
+        spawn_enemy_bullet(commands, bullet.owner, pos, dir)
+    } else {
+        spawn_bullet(
+            commands,
+            pos,
+            dir,
+            velocity.length(),
+            bullet.lifetime.duration().as_secs_f32(),
+            bullet.damage,
+            bullet.owner,
+        )
+    };
+    commands
+        .entity(entity)
+        .insert(bullet)
+        .insert(Velocity::linear(velocity));
+    entity
+}
+
+// rapier moves the bullet and sweeps it between frames (ccd)
+// so fast bullets can't skip over a thin wall.
+// hits come in as collision events, see bullet_collision_rapier
+fn projectile_body(dir: Vec2, speed: f32) -> impl Bundle {
+    (
+        RigidBody::Dynamic,
+        Velocity::linear(dir * speed),
+        Ccd::enabled(),
+        Collider::ball(5.0),
+        Sensor,
+        ActiveEvents::COLLISION_EVENTS,
+    )
+}
+
+// fired by enemies at the player
+#[derive(Component)]
+pub struct EnemyBullet;
+
+pub fn spawn_enemy_bullet(
+    commands: &mut Commands,
+    owner: Option<Entity>,
+    pos: Vec3,
+    dir: Vec2,
+) -> Entity {
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: bullet_sprite(true),
             transform: Transform {
                 translation: pos,
                 rotation: Quat::from_rotation_arc_2d(Vec2::Y, dir),
@@ -283,27 +601,25 @@ fn spawn_bullet(commands: &mut Commands, pos: Vec3, dir: Vec2, lifetime: f32, da
             },
             ..default()
         })
-        .insert(Bullet::new(dir, lifetime, damage))
-        .insert(RigidBody::Dynamic)
-        .insert(Collider::ball(5.0))
-        .insert(Sensor);
+        .insert(Bullet::new(2.0, 5, owner))
+        .insert_bundle(projectile_body(dir, 400.))
+        .insert(EnemyBullet)
+        .id()
 }
 
 fn spawn_shotgun_bullet(
     commands: &mut Commands,
     pos: Vec3,
     dir: Vec2,
+    speed: f32,
     lifetime: f32,
     damage: u32,
+    owner: Option<Entity>,
     shotgun: impl Component,
-) {
+) -> Entity {
     commands
         .spawn_bundle(SpriteBundle {
-            sprite: Sprite {
-                color: Color::rgb(0.25, 0.25, 0.75),
-                custom_size: Some(Vec2::new(10., 20.)),
-                ..default()
-            },
+            sprite: bullet_sprite(false),
             transform: Transform {
                 translation: pos,
                 rotation: Quat::from_rotation_arc_2d(Vec2::Y, dir),
@@ -311,59 +627,78 @@ fn spawn_shotgun_bullet(
             },
             ..default()
         })
-        .insert(Bullet::new(dir, lifetime, damage))
-        .insert(RigidBody::Dynamic)
-        .insert(Collider::ball(5.0))
-        .insert(Sensor)
-        .insert(shotgun);
+        .insert(Bullet::new(lifetime, damage, owner))
+        .insert_bundle(projectile_body(dir, speed))
+        .insert(shotgun)
+        .id()
     // insert shotgun is the only difference
     // I don't know how to make it so I can do
     // spawn_bullet(...).insert(shotgun)
 }
 
-fn reload(mut q_gun: Query<&mut Gun>, time: Res<Time>) {
-    let mut gun = q_gun.single_mut();
-
-    if gun.state == GunState::Reloading {
-        //if gun.shots_left <= 0 {
-        //println!("Reloading {:?}", time.delta().as_secs_f32());
-        // take some time before you refill ammo
-        // this only runs when you are out of ammo
-        if gun.reload_timer.tick(time.delta()).just_finished() {
-            println!("Reload finished");
-            gun.shots_left = gun.clip_size;
-            gun.state = GunState::Ready;
+fn manual_reload(
+    player_inputs: Res<PlayerInputs>,
+    mut q_gun: Query<(&Player, &mut Gun), Without<Dead>>,
+) {
+    for (player, mut gun) in q_gun.iter_mut() {
+        if player_inputs.get(player).reload {
+            gun.start_reload();
         }
     }
 }
 
-fn immediate_reload(mut q_gun: Query<&mut Gun>, mut ev_reload: EventReader<ImmediateReloadEvent>) {
-    for _ in ev_reload.iter() {
-        let mut gun = q_gun.single_mut();
+fn reload(mut q_gun: Query<&mut Gun>, time: Res<GameTime>) {
+    for mut gun in q_gun.iter_mut() {
+        if gun.state != GunState::Reloading {
+            continue;
+        }
 
-        gun.shots_left = gun.clip_size;
-        gun.state = GunState::Ready;
+        // take some time before you refill ammo
+        if gun.reload_timer.tick(time.delta()).just_finished() {
+            match gun.reload_style {
+                ReloadStyle::FullClip => gun.shots_left = gun.clip_size,
+                ReloadStyle::ShellByShell => gun.shots_left += 1,
+            }
+
+            if gun.shots_left >= gun.clip_size {
+                println!("Reload finished");
+                gun.shots_left = gun.clip_size;
+                gun.state = GunState::Ready;
+            }
+        }
     }
 }
 
-fn move_bullet(mut q_bullet: Query<(&mut Transform, &Bullet)>, time: Res<Time>) {
-    for (mut transform, bullet) in &mut q_bullet {
-        // vec2 to vec3 with extend
-        transform.translation += (bullet.dir * time.delta_seconds() * 700.).extend(0.);
+fn immediate_reload(mut q_gun: Query<&mut Gun>, mut ev_reload: EventReader<ImmediateReloadEvent>) {
+    for ev in ev_reload.iter() {
+        if let Ok(mut gun) = q_gun.get_mut(ev.shooter) {
+            gun.shots_left = gun.clip_size;
+            gun.state = GunState::Ready;
+        }
     }
 }
 
 fn bullet_lifetime(
     mut commands: Commands,
-    mut q_bullet: Query<(Entity, &mut Bullet, Option<&ShotgunBullet>)>,
+    mut q_bullet: Query<(
+        Entity,
+        &mut Bullet,
+        Option<&ShotgunBullet>,
+        Option<&ShotFrom>,
+    )>,
     mut ev_shotgun_end: EventWriter<ShotgunBulletEndEvent>,
-    time: Res<Time>,
+    mut ev_bullet_end: EventWriter<BulletEndEvent>,
+    time: Res<GameTime>,
 ) {
-    for (entity, mut bullet, shotgun) in &mut q_bullet {
+    for (entity, mut bullet, shotgun, shot_from) in &mut q_bullet {
         if bullet.lifetime.tick(time.delta()).just_finished() {
+            if let Some(shot_from) = shot_from {
+                ev_bullet_end.send(shot_from.end(bullet.owner, BulletEndReason::Expired));
+            }
             if let Some(shotgun) = shotgun {
                 ev_shotgun_end.send(ShotgunBulletEndEvent {
-                    side: shotgun.side,
+                    shooter: bullet.owner,
+                    pellet: shotgun.pellet,
                     shot_number: shotgun.shot_number,
                     reason: BulletEndReason::Expired,
                 });
@@ -374,61 +709,259 @@ fn bullet_lifetime(
 }
 
 fn bullet_collision_rapier(
-    rapier_context: Res<RapierContext>,
-    q_bullets: Query<(Entity, &Transform, &Bullet, Option<&ShotgunBullet>)>,
-    mut q_enemies: Query<(Entity, &mut Health), With<Enemy>>,
-    q_walls: Query<(Entity, &Wall)>,
+    mut ev_collision: EventReader<CollisionEvent>,
+    mut q_bullets: Query<(
+        &mut Transform,
+        &mut Velocity,
+        &Bullet,
+        Option<&ShotgunBullet>,
+        Option<&EnemyBullet>,
+        Option<&mut Piercing>,
+        Option<&mut Ricochet>,
+        Option<&Explosive>,
+        Option<&mut ShotFrom>,
+    )>,
+    q_enemies: Query<(Entity, &Transform), (With<Enemy>, Without<Bullet>, Without<Dying>)>,
+    q_player: Query<(), (With<Player>, Without<Dead>)>,
+    q_walls: Query<
+        (
+            Entity,
+            &Transform,
+            &Collider,
+            Option<&Breakable>,
+            Option<&Reflective>,
+            Option<&OneWay>,
+            Option<&Door>,
+        ),
+        (With<Wall>, Without<Bullet>),
+    >,
     mut commands: Commands,
     mut ev_bullet_hit: EventWriter<BulletHitEvent>,
     mut ev_shotgun_end: EventWriter<ShotgunBulletEndEvent>,
+    mut ev_damage: EventWriter<DamageEvent>,
+    mut ev_bullet_end: EventWriter<BulletEndEvent>,
 ) {
-    for bullet in q_bullets.iter() {
-        for (enemy, mut hp) in q_enemies.iter_mut() {
-            // loop over every bullet and every enemy looking for pairs
-            if rapier_context.intersection_pair(bullet.0, enemy) == Some(true) {
-                if let Some(shotgun) = bullet.3 {
-                    ev_shotgun_end.send(ShotgunBulletEndEvent {
-                        side: shotgun.side,
-                        shot_number: shotgun.shot_number,
-                        reason: BulletEndReason::HitEnemy,
-                    });
-                }
+    // a bullet can start touching two things in the same step
+    // but it only ends once
+    let mut ended: Vec<Entity> = Vec::new();
+    // blown up after the loop so the enemy query is free
+    let mut explosions = Vec::new();
 
-                ev_bullet_hit.send(BulletHitEvent {
-                    pos: bullet.1.translation.truncate(),
+    for ev in ev_collision.iter() {
+        let (a, b) = match ev {
+            CollisionEvent::Started(a, b, _) => (*a, *b),
+            CollisionEvent::Stopped(..) => continue,
+        };
+
+        // either side could be the bullet
+        let (entity, other) = if q_bullets.contains(a) {
+            (a, b)
+        } else if q_bullets.contains(b) {
+            (b, a)
+        } else {
+            continue;
+        };
+        if ended.contains(&entity) {
+            continue;
+        }
+
+        let (
+            mut transform,
+            mut velocity,
+            bullet,
+            shotgun,
+            enemy_bullet,
+            mut piercing,
+            mut ricochet,
+            explosive,
+            mut shot_from,
+        ) = q_bullets.get_mut(entity).unwrap();
+        let pos = transform.translation.truncate();
+        let mut end = false;
+        // already took the bullet's damage, an explosion doesn't hit it twice
+        let mut direct_hit = None;
+
+        if q_enemies.contains(other) {
+            // enemies can't shoot each other
+            if enemy_bullet.is_some() {
+                continue;
+            }
+
+            if let Some(shotgun) = shotgun {
+                ev_shotgun_end.send(ShotgunBulletEndEvent {
+                    shooter: bullet.owner,
+                    pellet: shotgun.pellet,
+                    shot_number: shotgun.shot_number,
+                    reason: BulletEndReason::HitEnemy,
                 });
-                hp.take_damage(bullet.2.damage);
+            }
 
-                commands.entity(bullet.0).despawn();
-                //commands.entity(enemy).despawn();
+            ev_bullet_hit.send(BulletHitEvent { pos, target: other });
+            direct_hit = Some(other);
+            ev_damage.send(DamageEvent {
+                target: other,
+                source: bullet.owner,
+                amount: bullet.damage,
+                kind: DamageKind::Bullet,
+                position: pos,
+            });
+
+            // piercing bullets keep going through a few enemies
+            end = match piercing.as_mut() {
+                Some(piercing) if piercing.hits_left > 0 => {
+                    piercing.hits_left -= 1;
+                    false
+                }
+                _ => true,
+            };
+            if let Some(shot_from) = shot_from.as_mut() {
+                shot_from.hit = true;
+                if end {
+                    ev_bullet_end.send(shot_from.end(bullet.owner, BulletEndReason::HitEnemy));
+                }
+            }
+        } else if q_player.contains(other) {
+            if enemy_bullet.is_none() {
+                continue;
             }
-        }
 
-        for (wall_ent, _) in q_walls.iter() {
-            if rapier_context.intersection_pair(bullet.0, wall_ent) == Some(true) {
-                if let Some(shotgun_bullet) = bullet.3 {
-                    ev_shotgun_end.send(ShotgunBulletEndEvent {
-                        side: shotgun_bullet.side,
-                        shot_number: shotgun_bullet.shot_number,
-                        reason: BulletEndReason::HitWall,
-                    });
+            ev_damage.send(DamageEvent {
+                target: other,
+                source: bullet.owner,
+                amount: bullet.damage,
+                kind: DamageKind::Bullet,
+                position: pos,
+            });
+            end = true;
+        } else if let Ok((_, wall_trans, wall_collider, breakable, reflective, one_way, door)) =
+            q_walls.get(other)
+        {
+            // an open door is just a gap
+            if door.map(|door| door.open).unwrap_or(false) {
+                continue;
+            }
+            if one_way.is_some() && velocity.linvel.dot(OneWay::direction(wall_trans)) > 0. {
+                continue;
+            }
+
+            // reflective walls bounce everything without using up ricochets
+            let ricochets = match ricochet.as_mut() {
+                Some(ricochet) if reflective.is_none() && ricochet.bounces_left > 0 => {
+                    Some(ricochet)
+                }
+                _ => None,
+            };
+            if reflective.is_some() || ricochets.is_some() {
+                if bounce(&mut velocity.linvel, pos, wall_trans, wall_collider) {
+                    if let Some(ricochet) = ricochets {
+                        ricochet.bounces_left -= 1;
+                    }
+                    transform.rotation =
+                        Quat::from_rotation_arc_2d(Vec2::Y, velocity.linvel.normalize_or_zero());
                 }
+                continue;
+            }
+
+            if breakable.is_some() {
+                direct_hit = Some(other);
+                ev_damage.send(DamageEvent {
+                    target: other,
+                    source: bullet.owner,
+                    amount: bullet.damage,
+                    kind: DamageKind::Bullet,
+                    position: pos,
+                });
+            }
+
+            if let Some(shotgun_bullet) = shotgun {
+                ev_shotgun_end.send(ShotgunBulletEndEvent {
+                    shooter: bullet.owner,
+                    pellet: shotgun_bullet.pellet,
+                    shot_number: shotgun_bullet.shot_number,
+                    reason: BulletEndReason::HitWall,
+                });
+            }
+
+            ev_bullet_hit.send(BulletHitEvent { pos, target: other });
+
+            if let Some(shot_from) = shot_from.as_ref() {
+                ev_bullet_end.send(shot_from.end(bullet.owner, BulletEndReason::HitWall));
+            }
+            end = true;
+        }
 
-                // BulletHitEvent
-                // would go here maybe?
-                // it doesn't really do anything yet.
-                // I made it to test events. But it's ambiguous if a hit is an enemy or wall
+        if end {
+            if let Some(explosive) = explosive {
+                explosions.push((
+                    pos,
+                    explosive.radius,
+                    explosive.damage,
+                    bullet.owner,
+                    direct_hit,
+                ));
+            }
+            commands.entity(entity).despawn();
+            ended.push(entity);
+        }
+    }
 
-                commands.entity(bullet.0).despawn();
+    for (pos, radius, damage, owner, direct_hit) in explosions {
+        for (enemy, enemy_trans) in q_enemies.iter() {
+            if Some(enemy) != direct_hit
+                && enemy_trans.translation.truncate().distance(pos) < radius
+            {
+                ev_damage.send(DamageEvent {
+                    target: enemy,
+                    source: owner,
+                    amount: damage,
+                    kind: DamageKind::Explosion,
+                    position: pos,
+                });
+            }
+        }
+        for (wall, wall_trans, _, breakable, ..) in q_walls.iter() {
+            if breakable.is_some()
+                && Some(wall) != direct_hit
+                && wall_trans.translation.truncate().distance(pos) < radius
+            {
+                ev_damage.send(DamageEvent {
+                    target: wall,
+                    source: owner,
+                    amount: damage,
+                    kind: DamageKind::Explosion,
+                    position: pos,
+                });
             }
         }
     }
 }
 
-fn bullet_event(mut ev_bullet_hit: EventReader<BulletHitEvent>) {
-    for hit in ev_bullet_hit.iter() {
-        eprintln!("Bullet hit at {:?}", hit.pos);
+// flip the bullet's velocity off the side of the wall it went into
+// the walls are all boxes so the side is whichever one it's deepest past
+// done in the wall's own space since level walls can be rotated
+// false if it's already heading out
+fn bounce(dir: &mut Vec2, pos: Vec2, wall_trans: &Transform, wall_collider: &Collider) -> bool {
+    let half_size = match wall_collider.as_cuboid() {
+        Some(cuboid) => cuboid.half_extents(),
+        None => return false,
+    };
+    let to_local = wall_trans.rotation.inverse();
+    let offset = (to_local * (pos - wall_trans.translation.truncate()).extend(0.)).truncate();
+    let mut local_dir = (to_local * dir.extend(0.)).truncate();
+
+    if (offset.x / half_size.x).abs() > (offset.y / half_size.y).abs() {
+        if local_dir.x * offset.x >= 0. {
+            return false;
+        }
+        local_dir.x = -local_dir.x;
+    } else {
+        if local_dir.y * offset.y >= 0. {
+            return false;
+        }
+        local_dir.y = -local_dir.y;
     }
+    *dir = (wall_trans.rotation * local_dir.extend(0.)).truncate();
+    true
 }
 
 fn shotgun_event(mut ev_shotgun_hit: EventReader<ShotgunBulletEndEvent>) {
@@ -436,8 +969,8 @@ fn shotgun_event(mut ev_shotgun_hit: EventReader<ShotgunBulletEndEvent>) {
         match hit.reason {
             BulletEndReason::HitEnemy => {
                 eprintln!(
-                    "Shotgun hit on {:?} side. Number: {:?}",
-                    hit.side, hit.shot_number
+                    "Shotgun hit with pellet {:?}. Number: {:?}",
+                    hit.pellet, hit.shot_number
                 );
             }
             _ => (),
@@ -449,66 +982,169 @@ fn shotgun_check_shots(
     mut ev_shotgun_end: EventReader<ShotgunBulletEndEvent>,
     mut q_gauge: Query<&mut ShotgunGauge>,
 ) {
-    let mut gauge = q_gauge.single_mut();
-
     for ev in ev_shotgun_end.iter() {
-        match ev.reason {
-            BulletEndReason::HitEnemy => match ev.side {
-                BulletSide::Left => {
-                    gauge.hit_pairs[ev.shot_number as usize].left = Some(true);
-                }
-                BulletSide::Right => {
-                    gauge.hit_pairs[ev.shot_number as usize].right = Some(true);
-                }
-            },
-            BulletEndReason::Expired | BulletEndReason::HitWall => match ev.side {
-                BulletSide::Left => {
-                    gauge.hit_pairs[ev.shot_number as usize].left = Some(false);
-                }
-                BulletSide::Right => {
-                    gauge.hit_pairs[ev.shot_number as usize].right = Some(false);
-                }
-            },
+        let mut gauge = match ev.shooter.and_then(|shooter| q_gauge.get_mut(shooter).ok()) {
+            Some(gauge) => gauge,
+            None => continue,
+        };
+
+        let hit = match ev.reason {
+            BulletEndReason::HitEnemy => true,
+            BulletEndReason::Expired | BulletEndReason::HitWall => false,
+        };
+
+        // the shot might have been from a different weapon
+        // with more pellets or a bigger clip
+        if let Some(shot) = gauge.shots.get_mut(ev.shot_number as usize) {
+            if let Some(pellet) = shot.pellets.get_mut(ev.pellet as usize) {
+                // a piercing pellet that hit something then expired still hit
+                *pellet = Some(hit || *pellet == Some(true));
+            }
         }
     }
 }
 
 fn shotgun_check_gauge(
-    mut q_gauge: Query<&mut ShotgunGauge>,
+    mut q_gauge: Query<(Entity, &mut ShotgunGauge, &Gun)>,
     mut ev_reload: EventWriter<ImmediateReloadEvent>,
 ) {
-    let mut gauge = q_gauge.single_mut();
-
-    for (i, pair) in gauge.hit_pairs.iter_mut().enumerate() {
-        // check if both have something
-        if let Some(left) = pair.left {
-            if let Some(right) = pair.right {
-                // for 2 bools, only 4 options
-
-                // I want this to trigger an extra shot,
-                // but reload time is 2s
-                // and bullets can travel for 1s
-                // so getting an extra ammo could be fairly delayed based on how long it takes to hit
-                // and you'd already be halfway through a reload
-                // could be +1 ammo on the next reload. Or quick-reload. Half time or immediate
-                // 0.3s between shots
-                // shot, 0.3, shot, 0.3, shot (immediate best case)
-                // shot, 0.3, shot, 1.0, shot (immediate worst case or half)
-                // shot, 0.3, shot, 2.0, shot (normal reload)
-                if left && right {
-                    println!("Both hit. Shot: {:?}", i);
-                    ev_reload.send(ImmediateReloadEvent);
-                } else if !left && !right {
-                    println!("Both missed. Shot: {:?}", i);
-                } else if left {
-                    println!("Only left hit. Shot: {:?}", i);
-                } else {
-                    println!("Only right hit. Shot: {:?}", i);
-                }
+    for (shooter, mut gauge, gun) in q_gauge.iter_mut() {
+        check_gauge(shooter, &mut gauge, gun, &mut ev_reload);
+    }
+}
 
-                // done once, now clean up the hit_pairs so it doesn't print forever
-                *pair = HitPair::new();
+fn check_gauge(
+    shooter: Entity,
+    gauge: &mut ShotgunGauge,
+    gun: &Gun,
+    ev_reload: &mut EventWriter<ImmediateReloadEvent>,
+) {
+    for (i, shot) in gauge.shots.iter_mut().enumerate() {
+        if shot.checked {
+            continue;
+        }
+
+        // check if every pellet has something
+        if let Some(hits) = shot.hits() {
+            let pellets = shot.pellets.len() as u32;
+
+            // I want this to trigger an extra shot,
+            // but reload time is 2s
+            // and bullets can travel for 1s
+            // so getting an extra ammo could be fairly delayed based on how long it takes to hit
+            // and you'd already be halfway through a reload
+            // could be +1 ammo on the next reload. Or quick-reload. Half time or immediate
+            // 0.3s between shots
+            // shot, 0.3, shot, 0.3, shot (immediate best case)
+            // shot, 0.3, shot, 1.0, shot (immediate worst case or half)
+            // shot, 0.3, shot, 2.0, shot (normal reload)
+            if gun.perfect_shot.is_perfect(hits, pellets) {
+                println!("Perfect shot. {:?}/{:?} hit. Shot: {:?}", hits, pellets, i);
+                ev_reload.send(ImmediateReloadEvent { shooter });
+            } else {
+                println!("{:?}/{:?} hit. Shot: {:?}", hits, pellets, i);
             }
+
+            // done once, don't print it forever
+            shot.checked = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::{event::ManualEventReader, system::CommandQueue};
+
+    use super::*;
+    use crate::{
+        cartridge::Explosive,
+        enemy::EnemyKind,
+        headless::HeadlessApp,
+        health::Health,
+        input::{InputScript, PlayerInput},
+    };
+
+    fn gun_with(reload_style: ReloadStyle, shots_left: u32, state: GunState) -> Gun {
+        let mut gun = Gun::from_def(&WeaponDef::default());
+        gun.reload_style = reload_style;
+        gun.shots_left = shots_left;
+        gun.state = state;
+        gun
+    }
+
+    #[test]
+    fn empty_gun_mid_reload_is_reloading() {
+        let gun = gun_with(ReloadStyle::FullClip, 0, GunState::Reloading);
+        assert_eq!(gun.shoot(10.), Err(ShootError::Reloading));
+    }
+
+    #[test]
+    fn empty_gun_not_reloading_is_out_of_ammo() {
+        let gun = gun_with(ReloadStyle::FullClip, 0, GunState::Ready);
+        assert_eq!(gun.shoot(10.), Err(ShootError::OutOfAmmo));
+        let gun = gun_with(ReloadStyle::ShellByShell, 0, GunState::Reloading);
+        assert_eq!(gun.shoot(10.), Err(ShootError::OutOfAmmo));
+    }
+
+    #[test]
+    fn shell_by_shell_fires_what_is_loaded() {
+        let gun = gun_with(ReloadStyle::ShellByShell, 2, GunState::Reloading);
+        assert_eq!(gun.shoot(10.), Ok(()));
+    }
+
+    #[test]
+    fn explosions_dont_hit_the_enemy_twice() {
+        let mut sim = HeadlessApp::new(InputScript::default());
+        sim.run_ticks(1);
+
+        // tanks: 10 hp, 1 armor, half damage from explosions
+        let hit = sim.spawn_enemy(EnemyKind::Tank, Vec2::new(400., 300.));
+        let nearby = sim.spawn_enemy(EnemyKind::Tank, Vec2::new(400., 380.));
+
+        let world = sim.world();
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, world);
+        let bullet = respawn_bullet(
+            &mut commands,
+            Vec3::new(250., 300., 0.),
+            Vec2::new(700., 0.),
+            Bullet::new(1., 3, None),
+            false,
+        );
+        commands.entity(bullet).insert(Explosive {
+            radius: 100.,
+            damage: 4,
+        });
+        queue.apply(world);
+
+        sim.run_ticks(30);
+        let world = sim.world();
+        // 3 from the bullet less 1 armor, and no blast
+        assert_eq!(world.get::<Health>(hit).unwrap().current(), 8);
+        // half of 4 less 1 armor
+        assert_eq!(world.get::<Health>(nearby).unwrap().current(), 9);
+    }
+
+    #[test]
+    fn held_trigger_reports_each_refusal_once() {
+        let shoot = PlayerInput {
+            aim: Vec2::new(0., 300.),
+            fire: true,
+            ..default()
+        };
+        let mut sim = HeadlessApp::new(InputScript::default().then(60, shoot));
+
+        let mut reader = ManualEventReader::<ShootErrorEvent>::default();
+        let mut errors = Vec::new();
+        for _ in 0..60 {
+            sim.run_ticks(1);
+            let events = sim.world().resource::<Events<ShootErrorEvent>>();
+            errors.extend(reader.iter(events).map(|ev| ev.error));
         }
+
+        // held through the cooldown between the two shells
+        assert!(errors.contains(&ShootError::ShotCooldown), "{:?}", errors);
+        // but not every tick it's held
+        assert!(errors.len() < 10, "{:?}", errors);
     }
 }
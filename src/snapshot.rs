@@ -0,0 +1,709 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cartridge::{
+        self, CartridgeInventory, CartridgePickup, Explosive, PickupSpawner, Piercing, Ricochet,
+    },
+    damage::Invulnerable,
+    enemy::{self, Charge, Enemy, EnemyKind, RangedAttack},
+    health::{self, Dying, Health, HealthPickup, Regeneration, Shield},
+    level::{CurrentLevel, SelectedLevel},
+    player::{self, Dead, LocalPlayers},
+    replay::{ReplayPlayback, ReplayRecorder},
+    score::Score,
+    shooting::{self, Bullet, EnemyBullet, Gun, ShotFrom, Shotgun, ShotgunBullet, ShotgunGauge},
+    stats::RunStats,
+    tick::{self, GameRng, GameTime, TickApp, TickStage},
+    wall::{Breakable, Debris, Door},
+    wave::WaveDirector,
+    weapon::{self, Weapon},
+    Player, Wall,
+};
+
+// a fight in progress written to a file and loaded back into the same world:
+// every player with their gun, gauge and cartridges, every enemy, bullet and pickup,
+// the walls that can change, the waves, the pickup spawner, the score and the rng.
+// for checkpoints, and for getting back to the middle of a fight that went wrong.
+// save one from the pause menu, cargo run -- --snapshot file carries on from it
+//
+// rapier's own state (who's touching who) and systems' Locals aren't in it,
+// so a loaded fight can slowly drift from how the original went on.
+// dying enemies and debris are left out, as far as the fight cares they're gone
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_tick_system_to_stage(TickStage::End, save_snapshot.exclusive_system())
+            .add_tick_system_to_stage(TickStage::End, restore_snapshot);
+    }
+}
+
+// insert this to save the fight at the end of the tick
+pub struct SaveSnapshot;
+
+// insert this to load a snapshot once the next fight is set up.
+// its level has to be the SelectedLevel, see Snapshot::prepare
+pub struct LoadSnapshot(pub Snapshot);
+
+// where to write snapshots, one file per save
+pub struct SnapshotExport {
+    pub dir: PathBuf,
+}
+
+// the last snapshot written, for the pause menu
+pub struct LastSnapshot(pub PathBuf);
+
+// on a player whose Gun came from a snapshot
+// so weapon::apply_weapon_defs doesn't give them a fresh one
+#[derive(Component)]
+pub struct Restored;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    // the game version it was saved on
+    pub version: String,
+    pub ticks_per_second: u32,
+    pub level: String,
+    pub tick: u64,
+    // ticks not spent paused, what GameTime::seconds counts
+    pub played: u64,
+    pub seed: u64,
+    // how far the rng had got, see GameRng::words
+    pub rng_words: u64,
+    // one per local player, in slot order
+    pub players: Vec<SavedPlayer>,
+    pub enemies: Vec<SavedEnemy>,
+    pub bullets: Vec<SavedBullet>,
+    pub cartridge_pickups: Vec<(Vec2, CartridgePickup)>,
+    pub health_pickups: Vec<(Vec2, HealthPickup)>,
+    // breakable walls that are still up and doors
+    pub walls: Vec<SavedWall>,
+    pub pickup_spawner: PickupSpawner,
+    pub waves: WaveDirector,
+    pub score: Score,
+    pub stats: RunStats,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedPlayer {
+    pub slot: usize,
+    // dead players stay dead, in co-op the others carry on
+    pub dead: bool,
+    pub translation: Vec3,
+    // the weapon file, like weapons/shotgun.weapon.ron
+    pub weapon: String,
+    pub gun: Gun,
+    pub gauge: Option<ShotgunGauge>,
+    pub cartridges: CartridgeInventory,
+    pub health: Health,
+    pub regeneration: Option<Regeneration>,
+    pub shield: Option<Shield>,
+    pub invulnerable: Option<Invulnerable>,
+}
+
+// the rest of an enemy comes from its kind
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedEnemy {
+    pub kind: EnemyKind,
+    pub position: Vec2,
+    pub health: Health,
+    pub charge: Option<Charge>,
+    pub ranged: Option<RangedAttack>,
+}
+
+// entities are different once it's loaded,
+// so who fired a bullet is saved as who they were in the snapshot
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum Owner {
+    // by slot
+    Player(usize),
+    // index into Snapshot::enemies
+    Enemy(usize),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedBullet {
+    pub translation: Vec3,
+    pub velocity: Vec2,
+    // with how long it has left
+    pub bullet: Bullet,
+    pub owner: Option<Owner>,
+    pub enemy: bool,
+    pub shotgun: Option<ShotgunBullet>,
+    pub shot_from: Option<ShotFrom>,
+    pub piercing: Option<Piercing>,
+    pub explosive: Option<Explosive>,
+    pub ricochet: Option<Ricochet>,
+}
+
+// the level builds the walls again,
+// these are matched up with them by where they are
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedWall {
+    pub position: Vec2,
+    pub health: Option<Health>,
+    pub door: Option<Door>,
+}
+
+impl Snapshot {
+    // None if there's no fight going on, or everyone is dead
+    fn take(world: &mut World) -> Option<Self> {
+        let mut player_entities = Vec::new();
+        let mut players = Vec::new();
+        let mut q_players = world.query::<(
+            Entity,
+            &Player,
+            Option<&Dead>,
+            &Transform,
+            &Weapon,
+            &Gun,
+            Option<&ShotgunGauge>,
+            &CartridgeInventory,
+            &Health,
+            Option<&Regeneration>,
+            Option<&Shield>,
+            Option<&Invulnerable>,
+        )>();
+        for (
+            entity,
+            slot,
+            dead,
+            transform,
+            weapon,
+            gun,
+            gauge,
+            cartridges,
+            health,
+            regen,
+            shield,
+            invuln,
+        ) in q_players.iter(world)
+        {
+            let weapon = world
+                .resource::<AssetServer>()
+                .get_handle_path(&weapon.0)
+                .map(|path| path.path().to_string_lossy().into_owned())
+                .unwrap_or_else(|| weapon::STARTING_WEAPON.to_string());
+
+            player_entities.push((entity, slot.0));
+            players.push(SavedPlayer {
+                slot: slot.0,
+                dead: dead.is_some(),
+                translation: transform.translation,
+                weapon,
+                gun: gun.clone(),
+                gauge: gauge.cloned(),
+                cartridges: cartridges.clone(),
+                health: health.clone(),
+                regeneration: regen.cloned(),
+                shield: shield.cloned(),
+                invulnerable: invuln.cloned(),
+            });
+        }
+        if players.iter().all(|player| player.dead) {
+            return None;
+        }
+        players.sort_by_key(|player| player.slot);
+
+        let mut enemy_entities = Vec::new();
+        let mut enemies = Vec::new();
+        let mut q_enemies = world.query_filtered::<(
+            Entity,
+            &Enemy,
+            &Transform,
+            &Health,
+            Option<&Charge>,
+            Option<&RangedAttack>,
+        ), Without<Dying>>();
+        for (entity, enemy, transform, health, charge, ranged) in q_enemies.iter(world) {
+            enemy_entities.push(entity);
+            enemies.push(SavedEnemy {
+                kind: enemy.0,
+                position: transform.translation.truncate(),
+                health: health.clone(),
+                charge: charge.cloned(),
+                ranged: ranged.cloned(),
+            });
+        }
+
+        let owner = |entity: Option<Entity>| {
+            let entity = entity?;
+            if let Some((_, slot)) = player_entities.iter().find(|(player, _)| *player == entity) {
+                return Some(Owner::Player(*slot));
+            }
+            enemy_entities
+                .iter()
+                .position(|enemy| *enemy == entity)
+                .map(Owner::Enemy)
+        };
+
+        let mut q_bullets = world.query::<(
+            &Transform,
+            &Velocity,
+            &Bullet,
+            Option<&EnemyBullet>,
+            Option<&ShotgunBullet>,
+            Option<&ShotFrom>,
+            Option<&Piercing>,
+            Option<&Explosive>,
+            Option<&Ricochet>,
+        )>();
+        let bullets = q_bullets
+            .iter(world)
+            .map(
+                |(
+                    transform,
+                    velocity,
+                    bullet,
+                    enemy,
+                    shotgun,
+                    shot_from,
+                    piercing,
+                    explosive,
+                    ricochet,
+                )| {
+                    SavedBullet {
+                        translation: transform.translation,
+                        velocity: velocity.linvel,
+                        bullet: bullet.clone(),
+                        owner: owner(bullet.owner),
+                        enemy: enemy.is_some(),
+                        shotgun: shotgun.cloned(),
+                        shot_from: shot_from.cloned(),
+                        piercing: piercing.cloned(),
+                        explosive: explosive.cloned(),
+                        ricochet: ricochet.cloned(),
+                    }
+                },
+            )
+            .collect();
+
+        let cartridge_pickups = world
+            .query::<(&Transform, &CartridgePickup)>()
+            .iter(world)
+            .map(|(transform, pickup)| (transform.translation.truncate(), pickup.clone()))
+            .collect();
+        let health_pickups = world
+            .query::<(&Transform, &HealthPickup)>()
+            .iter(world)
+            .map(|(transform, pickup)| (transform.translation.truncate(), pickup.clone()))
+            .collect();
+
+        let walls = world
+            .query_filtered::<(&Transform, Option<&Health>, Option<&Door>), (
+                With<Wall>,
+                Or<(With<Breakable>, With<Door>)>,
+                Without<Dying>,
+            )>()
+            .iter(world)
+            .map(|(transform, health, door)| SavedWall {
+                position: transform.translation.truncate(),
+                health: health.cloned(),
+                door: door.copied(),
+            })
+            .collect();
+
+        let rng = world.resource::<GameRng>();
+        Some(Snapshot {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            ticks_per_second: tick::TICKS_PER_SECOND,
+            level: world.resource::<SelectedLevel>().0.clone(),
+            tick: world.resource::<GameTime>().tick(),
+            played: world.resource::<GameTime>().played(),
+            seed: rng.seed(),
+            rng_words: rng.words(),
+            players,
+            enemies,
+            bullets,
+            cartridge_pickups,
+            health_pickups,
+            walls,
+            pickup_spawner: world.get_resource::<PickupSpawner>()?.clone(),
+            waves: world.get_resource::<WaveDirector>()?.clone(),
+            score: world.resource::<Score>().clone(),
+            stats: world.resource::<RunStats>().clone(),
+        })
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        ron::from_str(&text).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+    }
+
+    // returns the file it wrote
+    pub fn save(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or(0);
+        let path = dir.join(format!("snapshot-{}.ron", millis));
+
+        // pretty so a value can be tweaked by hand to chase a bug
+        let text = ron::ser::to_string_pretty(self, PrettyConfig::default())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        fs::write(&path, text)?;
+        Ok(path)
+    }
+
+    // the next fight has to be built in this level with this many players
+    pub fn prepare(&self, selected_level: &mut SelectedLevel, local_players: &mut LocalPlayers) {
+        if self.version != env!("CARGO_PKG_VERSION")
+            || self.ticks_per_second != tick::TICKS_PER_SECOND
+        {
+            eprintln!(
+                "Snapshot was saved on version {} at {} ticks a second, it might not load right",
+                self.version, self.ticks_per_second
+            );
+        }
+        selected_level.0 = self.level.clone();
+        local_players.0 = self.players.len().max(1);
+    }
+}
+
+// set up an app to start its first fight from a snapshot
+pub fn load(app: &mut App, snapshot: Snapshot) {
+    let mut selected_level = SelectedLevel::default();
+    let mut local_players = LocalPlayers::default();
+    snapshot.prepare(&mut selected_level, &mut local_players);
+    app.insert_resource(selected_level)
+        .insert_resource(local_players)
+        .insert_resource(LoadSnapshot(snapshot));
+}
+
+fn save_snapshot(world: &mut World) {
+    if world.remove_resource::<SaveSnapshot>().is_none() {
+        return;
+    }
+
+    let snapshot = match Snapshot::take(world) {
+        Some(snapshot) => snapshot,
+        None => {
+            eprintln!("No fight to snapshot");
+            return;
+        }
+    };
+    let dir = match world.get_resource::<SnapshotExport>() {
+        Some(export) => export.dir.clone(),
+        None => return,
+    };
+
+    match snapshot.save(&dir) {
+        Ok(path) => {
+            println!("Snapshot written to {:?}", path);
+            world.insert_resource(LastSnapshot(path));
+        }
+        Err(err) => eprintln!("Couldn't write snapshot: {}", err),
+    }
+}
+
+// waits for the level to be built, so it doesn't move the players
+// or drop the first pickup on top of the loaded fight
+fn restore_snapshot(
+    mut commands: Commands,
+    load: Option<Res<LoadSnapshot>>,
+    current_level: Option<Res<CurrentLevel>>,
+    asset_server: Res<AssetServer>,
+    mut q_player: Query<(Entity, &Player, &mut Sprite), Without<Wall>>,
+    q_leftovers: Query<
+        Entity,
+        Or<(
+            With<Enemy>,
+            With<Bullet>,
+            With<CartridgePickup>,
+            With<HealthPickup>,
+            With<Debris>,
+        )>,
+    >,
+    mut q_walls: Query<
+        (
+            Entity,
+            &Transform,
+            Option<&Breakable>,
+            Option<&mut Door>,
+            &mut Sprite,
+        ),
+        With<Wall>,
+    >,
+    mut game_time: ResMut<GameTime>,
+    mut rng: ResMut<GameRng>,
+    director: Option<ResMut<WaveDirector>>,
+) {
+    let snapshot = match &load {
+        Some(load) => &load.0,
+        None => return,
+    };
+    if !current_level.map(|level| level.is_built()).unwrap_or(false) {
+        return;
+    }
+    if q_player.is_empty() {
+        return;
+    }
+    commands.remove_resource::<LoadSnapshot>();
+
+    // whatever the fight had done so far
+    for entity in q_leftovers.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    // by slot
+    let mut players = Vec::new();
+    for (player, slot, mut sprite) in q_player.iter_mut() {
+        let saved = match snapshot.players.iter().find(|saved| saved.slot == slot.0) {
+            Some(saved) => saved,
+            None => continue,
+        };
+        players.push((slot.0, player));
+
+        let mut player_commands = commands.entity(player);
+        player_commands
+            .insert(Transform::from_translation(saved.translation))
+            .insert(Weapon(asset_server.load(&saved.weapon)))
+            .insert(saved.gun.clone())
+            .insert(saved.cartridges.clone())
+            .insert(saved.health.clone())
+            .insert(Restored);
+        if saved.gun.pellets > 1 {
+            player_commands.insert(Shotgun);
+        } else {
+            player_commands.remove::<Shotgun>();
+        }
+        match &saved.gauge {
+            Some(gauge) => player_commands.insert(gauge.clone()),
+            None => player_commands.remove::<ShotgunGauge>(),
+        };
+        match &saved.regeneration {
+            Some(regen) => player_commands.insert(regen.clone()),
+            None => player_commands.remove::<Regeneration>(),
+        };
+        match &saved.shield {
+            Some(shield) => player_commands.insert(shield.clone()),
+            None => player_commands.remove::<Shield>(),
+        };
+        match &saved.invulnerable {
+            Some(invulnerable) => player_commands.insert(invulnerable.clone()),
+            None => player_commands.remove::<Invulnerable>(),
+        };
+        if saved.dead {
+            player_commands.insert(Dead);
+            sprite.color = Color::GRAY;
+        } else {
+            player_commands.remove::<Dead>();
+            sprite.color = player::player_color(slot.0);
+        }
+    }
+
+    let mut enemies = Vec::with_capacity(snapshot.enemies.len());
+    for saved in snapshot.enemies.iter() {
+        let mut enemy = enemy::spawn_enemy(&mut commands, saved.kind, saved.position);
+        enemy.insert(saved.health.clone());
+        if let Some(charge) = &saved.charge {
+            enemy.insert(charge.clone());
+        }
+        if let Some(ranged) = &saved.ranged {
+            enemy.insert(ranged.clone());
+        }
+        enemies.push(enemy.id());
+    }
+
+    for saved in snapshot.bullets.iter() {
+        let mut bullet = saved.bullet.clone();
+        bullet.owner = match saved.owner {
+            Some(Owner::Player(slot)) => players
+                .iter()
+                .find(|(player_slot, _)| *player_slot == slot)
+                .map(|(_, player)| *player),
+            Some(Owner::Enemy(index)) => enemies.get(index).copied(),
+            None => None,
+        };
+        let entity = shooting::respawn_bullet(
+            &mut commands,
+            saved.translation,
+            saved.velocity,
+            bullet,
+            saved.enemy,
+        );
+
+        let mut bullet = commands.entity(entity);
+        if let Some(shotgun) = &saved.shotgun {
+            bullet.insert(shotgun.clone());
+        }
+        if let Some(shot_from) = &saved.shot_from {
+            bullet.insert(shot_from.clone());
+        }
+        if let Some(piercing) = &saved.piercing {
+            bullet.insert(piercing.clone());
+        }
+        if let Some(explosive) = &saved.explosive {
+            bullet.insert(explosive.clone());
+        }
+        if let Some(ricochet) = &saved.ricochet {
+            bullet.insert(ricochet.clone());
+        }
+    }
+
+    for (position, pickup) in snapshot.cartridge_pickups.iter() {
+        cartridge::spawn_cart_pickup(&mut commands, *position, pickup.kind);
+    }
+    for (position, pickup) in snapshot.health_pickups.iter() {
+        health::spawn_health_pickup(&mut commands, *position, pickup.clone());
+    }
+
+    for (entity, transform, breakable, door, mut sprite) in q_walls.iter_mut() {
+        if breakable.is_none() && door.is_none() {
+            continue;
+        }
+        let position = transform.translation.truncate();
+        let saved = match snapshot
+            .walls
+            .iter()
+            .find(|wall| wall.position.distance(position) < 0.5)
+        {
+            Some(saved) => saved,
+            None => {
+                // it was broken before the snapshot
+                if breakable.is_some() {
+                    commands.entity(entity).despawn_recursive();
+                }
+                continue;
+            }
+        };
+
+        if let Some(health) = &saved.health {
+            commands.entity(entity).insert(health.clone());
+        }
+        if let (Some(mut door), Some(saved_door)) = (door, saved.door) {
+            door.set_open(saved_door.open, &mut commands.entity(entity), &mut sprite);
+        }
+    }
+
+    game_time.restore(snapshot.tick, snapshot.played);
+    *rng = GameRng::restore(snapshot.seed, snapshot.rng_words);
+    if let Some(mut director) = director {
+        director.restore(snapshot.waves.clone());
+    }
+    commands.insert_resource(snapshot.pickup_spawner.clone());
+    commands.insert_resource(snapshot.score.clone());
+    commands.insert_resource(snapshot.stats.clone());
+
+    // a replay has to start from the first tick, so this run can't be one
+    commands.remove_resource::<ReplayRecorder>();
+    commands.remove_resource::<ReplayPlayback>();
+
+    println!("Snapshot loaded at tick {}", snapshot.tick);
+}
+
+// #[serde(with = "crate::snapshot::timer")] on a Timer field
+// saves how long it is, how far in it is and whether it repeats
+pub mod timer {
+    use std::time::Duration;
+
+    use bevy::prelude::Timer;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct SavedTimer {
+        duration: Duration,
+        elapsed: Duration,
+        repeating: bool,
+    }
+
+    pub fn serialize<S: Serializer>(timer: &Timer, serializer: S) -> Result<S::Ok, S::Error> {
+        SavedTimer {
+            duration: timer.duration(),
+            elapsed: timer.elapsed(),
+            repeating: timer.repeating(),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Timer, D::Error> {
+        let saved = SavedTimer::deserialize(deserializer)?;
+        let mut timer = Timer::new(saved.duration, saved.repeating);
+        timer.set_elapsed(saved.elapsed);
+        // a one shot timer that already went off would go off again on its next tick
+        if !saved.repeating && saved.elapsed >= saved.duration {
+            timer.tick(Duration::ZERO);
+        }
+        Ok(timer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        headless::{demo_script, HeadlessApp},
+        input::InputScript,
+        tick::RunSeed,
+    };
+
+    // the fields don't all have PartialEq, their ron is good enough to compare
+    fn as_ron<T: Serialize>(value: &T) -> String {
+        ron::to_string(value).unwrap()
+    }
+
+    #[test]
+    fn loading_a_snapshot_gets_the_same_fight_back() {
+        let mut sim = HeadlessApp::new(demo_script());
+        sim.app.insert_resource(RunSeed(Some(4)));
+        sim.run_ticks(300);
+        let saved = Snapshot::take(sim.world()).expect("nobody to snapshot");
+        assert!(!saved.enemies.is_empty(), "no enemies to save yet");
+
+        // through the file format too
+        let saved: Snapshot = ron::from_str(&as_ron(&saved)).unwrap();
+
+        let mut sim = HeadlessApp::new(InputScript::default());
+        load(&mut sim.app, saved.clone());
+        for _ in 0..600 {
+            if !sim.world().contains_resource::<LoadSnapshot>() {
+                break;
+            }
+            sim.run_ticks(1);
+        }
+        assert!(
+            !sim.world().contains_resource::<LoadSnapshot>(),
+            "it never loaded"
+        );
+        let loaded = Snapshot::take(sim.world()).unwrap();
+
+        assert_eq!(loaded.tick, saved.tick);
+        assert_eq!(loaded.played, saved.played);
+        assert_eq!(loaded.seed, saved.seed);
+        assert_eq!(as_ron(&loaded.score), as_ron(&saved.score));
+        assert_eq!(as_ron(&loaded.waves), as_ron(&saved.waves));
+        assert_eq!(loaded.bullets.len(), saved.bullets.len());
+        assert_eq!(as_ron(&loaded.walls), as_ron(&saved.walls));
+
+        for (loaded, saved) in loaded.players.iter().zip(saved.players.iter()) {
+            assert_eq!(loaded.slot, saved.slot);
+            assert_eq!(loaded.weapon, saved.weapon);
+            assert_eq!(as_ron(&loaded.gun), as_ron(&saved.gun));
+            assert_eq!(as_ron(&loaded.health), as_ron(&saved.health));
+        }
+
+        // spawned in a different order maybe, and rapier gets one step at them
+        assert_eq!(loaded.enemies.len(), saved.enemies.len());
+        for saved in saved.enemies.iter() {
+            assert!(
+                loaded.enemies.iter().any(|loaded| loaded.kind == saved.kind
+                    && loaded.position.distance(saved.position) < 5.
+                    && as_ron(&loaded.health) == as_ron(&saved.health)),
+                "lost the {:?} at {}",
+                saved.kind,
+                saved.position
+            );
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use bevy::{ecs::schedule::ShouldRun, prelude::*};
+use bevy_rapier2d::prelude::*;
+
+use crate::{
+    cartridge::CartridgePickup, enemy::Enemy, health::HealthPickup, input::PlayerInputs,
+    level::Prop, player::GameOverEvent, shooting::Bullet, tick::TickApp, wall::Debris, Player,
+    Wall,
+};
+
+// Paused and GameOver are pushed on top of Playing
+// so the fight is still there underneath, frozen.
+// leaving Playing for good (restart, back to the menu) tears the fight down
+// and entering Playing builds a new one
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AppState {
+    Menu,
+    Playing,
+    Paused,
+    GameOver,
+}
+
+pub struct StatePlugin;
+
+impl Plugin for StatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_tick_system(toggle_pause)
+            .add_tick_system_set(SystemSet::on_update(AppState::Playing).with_system(game_over))
+            .add_tick_system_set(SystemSet::on_enter(AppState::Playing).with_system(resume_physics))
+            .add_tick_system_set(
+                SystemSet::on_resume(AppState::Playing).with_system(resume_physics),
+            )
+            .add_tick_system_set(SystemSet::on_pause(AppState::Playing).with_system(pause_physics))
+            .add_tick_system_set(
+                SystemSet::on_exit(AppState::Playing)
+                    .with_system(despawn_with::<Player>)
+                    .with_system(despawn_with::<Wall>)
+                    .with_system(despawn_with::<Prop>)
+                    .with_system(despawn_with::<Debris>)
+                    .with_system(despawn_with::<Enemy>)
+                    .with_system(despawn_with::<Bullet>)
+                    .with_system(despawn_with::<CartridgePickup>)
+                    .with_system(despawn_with::<HealthPickup>),
+            );
+    }
+}
+
+// for systems outside the tick, which doesn't have the state driver.
+// true while the state is on top of the stack, like SystemSet::on_update
+pub fn in_state(state: AppState) -> impl FnMut(Res<State<AppState>>) -> ShouldRun {
+    move |current: Res<State<AppState>>| {
+        if *current.current() == state {
+            ShouldRun::Yes
+        } else {
+            ShouldRun::No
+        }
+    }
+}
+
+// anyone can pause
+fn toggle_pause(player_inputs: Res<PlayerInputs>, mut state: ResMut<State<AppState>>) {
+    if !player_inputs.any(|input| input.pause) {
+        return;
+    }
+
+    // errors if a change is already queued this frame, that's fine
+    let _ = match state.current() {
+        AppState::Playing => state.push(AppState::Paused),
+        AppState::Paused => state.pop(),
+        _ => Ok(()),
+    };
+}
+
+fn game_over(mut ev_game_over: EventReader<GameOverEvent>, mut state: ResMut<State<AppState>>) {
+    for _ in ev_game_over.iter() {
+        let _ = state.push(AppState::GameOver);
+    }
+}
+
+// stop rapier pushing things around while the game is frozen
+fn pause_physics(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.physics_pipeline_active = false;
+}
+
+fn resume_physics(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.physics_pipeline_active = true;
+}
+
+pub fn despawn_with<T: Component>(mut commands: Commands, q_entities: Query<Entity, With<T>>) {
+    for entity in q_entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        headless::HeadlessApp,
+        input::{InputScript, PlayerInput},
+        shooting::{Gun, ShootError},
+        tick::GameTime,
+    };
+
+    #[test]
+    fn paused_guns_stay_on_cooldown() {
+        let fire = PlayerInput {
+            aim: Vec2::new(0., 300.),
+            fire: true,
+            ..default()
+        };
+        let pause = PlayerInput {
+            pause: true,
+            ..default()
+        };
+        let mut sim = HeadlessApp::new(
+            InputScript::default()
+                .then(10, default())
+                .then(1, fire)
+                .then(1, pause)
+                .then(120, default()),
+        );
+        sim.run_ticks(12);
+        let played = sim.world().resource::<GameTime>().played();
+
+        // two seconds paused, the shotgun only needs 0.3
+        sim.run_ticks(120);
+        let world = sim.world();
+        assert_eq!(
+            *world.resource::<State<AppState>>().current(),
+            AppState::Paused
+        );
+        let time = world.resource::<GameTime>();
+        assert!(time.played() <= played + 1, "the clock moved while paused");
+
+        let now = time.seconds() as f32;
+        let mut q_gun = world.query_filtered::<&Gun, With<Player>>();
+        let gun = q_gun.single(world);
+        assert!(gun.last_shot.is_finite(), "it never fired");
+        assert_eq!(
+            gun.shoot(now - gun.last_shot),
+            Err(ShootError::ShotCooldown)
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    damage::{self, DamageDealt},
+    health::{self, DeathEvent, EntityKind},
+    player::{self, Dead, GameOverEvent},
+    score::Score,
+    shooting::{BulletEndEvent, BulletEndReason, ImmediateReloadEvent, ShotFiredEvent},
+    state::AppState,
+    tick::{self, GameRng, GameTime, TickApp},
+    wave::WaveDirector,
+    Player,
+};
+
+// numbers about the current run for balancing
+// written out as json when the last player dies if StatsExport is there
+pub struct StatsPlugin;
+
+impl Plugin for StatsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RunStats>()
+            .add_tick_system_set(
+                SystemSet::on_enter(AppState::Playing)
+                    .with_system(reset_stats.after(tick::start_run)),
+            )
+            .add_tick_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(count_shots)
+                    .with_system(count_bullet_ends)
+                    .with_system(count_perfect_shots)
+                    .with_system(count_damage.after(damage::apply_damage))
+                    .with_system(count_kills.after(health::death))
+                    .with_system(time_alive)
+                    .with_system(export_on_game_over.after(player::player_death)),
+            )
+            // quitting to the menu or restarting halfway through a run
+            .add_tick_system_set(SystemSet::on_exit(AppState::Playing).with_system(export_on_exit));
+    }
+}
+
+// where to write the json, one file per run
+pub struct StatsExport {
+    pub dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct WeaponStats {
+    // trigger pulls
+    pub shots: u32,
+    // a shotgun shot is several bullets
+    pub bullets: u32,
+    pub hits: u32,
+    pub hit_wall: u32,
+    pub expired: u32,
+    // hits / bullets that have ended
+    pub accuracy: f32,
+}
+
+impl WeaponStats {
+    fn update_accuracy(&mut self) {
+        let ended = self.hits + self.hit_wall + self.expired;
+        self.accuracy = if ended == 0 {
+            0.
+        } else {
+            self.hits as f32 / ended as f32
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct RunStats {
+    // by weapon name
+    pub weapons: BTreeMap<String, WeaponStats>,
+    pub perfect_shots: u32,
+    pub damage_dealt: u32,
+    // to health
+    pub damage_taken: u32,
+    // soaked up by the shield
+    pub damage_shielded: u32,
+    pub kills: u32,
+    pub kills_by_kind: BTreeMap<String, u32>,
+    // seconds
+    pub time_alive: f32,
+    // play it again with --seed
+    pub seed: u64,
+    // filled in by finish
+    pub wave: u32,
+    pub score: u64,
+    // a run that ended in a game over has already been written
+    // by the time the fight is left
+    #[serde(skip)]
+    exported: bool,
+}
+
+impl RunStats {
+    fn weapon(&mut self, name: &str) -> &mut WeaponStats {
+        self.weapons.entry(name.to_string()).or_default()
+    }
+
+    // the bits that come from other resources
+    pub fn finish(&mut self, wave: u32, score: u64) {
+        self.wave = wave;
+        self.score = score;
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    // returns the file it wrote
+    pub fn export(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or(0);
+        let path = dir.join(format!("run-{}.json", millis));
+
+        let json = self.to_json()?;
+        fs::write(&path, json)?;
+        Ok(path)
+    }
+}
+
+fn reset_stats(mut stats: ResMut<RunStats>, rng: Res<GameRng>) {
+    *stats = RunStats {
+        seed: rng.seed(),
+        ..default()
+    };
+}
+
+fn count_shots(mut ev_shot: EventReader<ShotFiredEvent>, mut stats: ResMut<RunStats>) {
+    for ev in ev_shot.iter() {
+        let weapon = stats.weapon(&ev.weapon);
+        weapon.shots += 1;
+        weapon.bullets += ev.bullets;
+    }
+}
+
+fn count_bullet_ends(mut ev_end: EventReader<BulletEndEvent>, mut stats: ResMut<RunStats>) {
+    for ev in ev_end.iter() {
+        let weapon = stats.weapon(&ev.weapon);
+        match ev.reason {
+            BulletEndReason::HitEnemy => weapon.hits += 1,
+            BulletEndReason::HitWall => weapon.hit_wall += 1,
+            BulletEndReason::Expired => weapon.expired += 1,
+        }
+        weapon.update_accuracy();
+    }
+}
+
+fn count_perfect_shots(
+    mut ev_reload: EventReader<ImmediateReloadEvent>,
+    mut stats: ResMut<RunStats>,
+) {
+    stats.perfect_shots += ev_reload.iter().count() as u32;
+}
+
+fn count_damage(
+    mut ev_dealt: EventReader<DamageDealt>,
+    q_player: Query<(), With<Player>>,
+    mut stats: ResMut<RunStats>,
+) {
+    for ev in ev_dealt.iter() {
+        if q_player.contains(ev.target) {
+            stats.damage_taken += ev.amount;
+            stats.damage_shielded += ev.shielded;
+        } else if ev.source.map(|source| q_player.contains(source)) == Some(true) {
+            stats.damage_dealt += ev.amount + ev.shielded;
+        }
+    }
+}
+
+fn count_kills(
+    mut ev_death: EventReader<DeathEvent>,
+    q_player: Query<(), With<Player>>,
+    mut stats: ResMut<RunStats>,
+) {
+    for ev in ev_death.iter() {
+        let kind = match ev.kind {
+            EntityKind::Enemy(kind) => kind,
+            _ => continue,
+        };
+        if ev.killer.map(|killer| q_player.contains(killer)) != Some(true) {
+            continue;
+        }
+
+        stats.kills += 1;
+        *stats
+            .kills_by_kind
+            .entry(format!("{:?}", kind))
+            .or_default() += 1;
+    }
+}
+
+fn time_alive(
+    q_player: Query<(), (With<Player>, Without<Dead>)>,
+    mut stats: ResMut<RunStats>,
+    time: Res<GameTime>,
+) {
+    if !q_player.is_empty() {
+        stats.time_alive += time.delta_seconds();
+    }
+}
+
+fn export_on_game_over(
+    mut ev_game_over: EventReader<GameOverEvent>,
+    mut stats: ResMut<RunStats>,
+    director: Res<WaveDirector>,
+    score: Res<Score>,
+    export: Option<Res<StatsExport>>,
+) {
+    if ev_game_over.iter().count() == 0 {
+        return;
+    }
+    export_stats(&mut stats, &director, &score, export.as_deref());
+}
+
+fn export_on_exit(
+    mut stats: ResMut<RunStats>,
+    director: Res<WaveDirector>,
+    score: Res<Score>,
+    export: Option<Res<StatsExport>>,
+) {
+    if stats.exported {
+        return;
+    }
+    export_stats(&mut stats, &director, &score, export.as_deref());
+}
+
+fn export_stats(
+    stats: &mut RunStats,
+    director: &WaveDirector,
+    score: &Score,
+    export: Option<&StatsExport>,
+) {
+    stats.finish(director.wave, score.points);
+    stats.exported = true;
+
+    if let Some(export) = export {
+        match stats.export(&export.dir) {
+            Ok(path) => println!("Run stats written to {:?}", path),
+            Err(err) => eprintln!("Couldn't write run stats: {}", err),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::time::Duration;
+
+use bevy::{
+    asset::{Asset, LoadState},
+    ecs::{
+        event::Event,
+        schedule::{IntoSystemDescriptor, ShouldRun, StateData},
+    },
+    prelude::*,
+};
+use bevy_rapier2d::prelude::*;
+use rand::{prelude::*, Error};
+use rand_chacha::ChaCha12Rng;
+
+use crate::state::AppState;
+
+// the fight runs in fixed ticks instead of once a frame,
+// so the same inputs and the same seed always play out the same way.
+//
+// a frame runs as many ticks as have built up since the last one,
+// which can be none. gameplay goes in TickStage::Update with
+// add_tick_system(_set) and uses GameTime and GameRng instead of Time and thread_rng.
+// events it sends go through add_tick_event so they last two ticks, not two frames.
+// drawing and menus stay in the normal stages and just show the latest tick
+//
+// the tick's own stages are single threaded. the parallel executor can run
+// two systems that both use GameRng in either order, and then the dice come out different
+pub struct TickPlugin;
+
+impl Plugin for TickPlugin {
+    fn build(&self, app: &mut App) {
+        let physics = |stage| {
+            SystemStage::parallel()
+                .with_system_set(RapierPhysicsPlugin::<NoUserData>::get_systems(stage))
+        };
+
+        let mut tick = Schedule::default().with_run_criteria(tick_due);
+        tick.add_stage(TickStage::Start, SystemStage::single_threaded())
+            .add_stage(TickStage::Update, SystemStage::single_threaded())
+            .add_stage(TickStage::End, SystemStage::single_threaded())
+            .add_stage(
+                PhysicsStages::SyncBackend,
+                physics(PhysicsStages::SyncBackend),
+            )
+            .add_stage(
+                PhysicsStages::StepSimulation,
+                physics(PhysicsStages::StepSimulation),
+            )
+            .add_stage(PhysicsStages::Writeback, physics(PhysicsStages::Writeback));
+
+        app.init_resource::<GameTime>()
+            .init_resource::<TickAssets>()
+            .init_resource::<RunSeed>()
+            .init_resource::<GameRng>()
+            .add_stage_after(CoreStage::Update, GameStage::Tick, tick)
+            // removals have to be caught every frame or they're missed
+            .add_stage_before(
+                CoreStage::Last,
+                PhysicsStages::DetectDespawn,
+                physics(PhysicsStages::DetectDespawn),
+            )
+            .add_tick_system_set(SystemSet::on_enter(AppState::Playing).with_system(start_run))
+            .add_tick_system_to_stage(TickStage::Start, count_played);
+    }
+}
+
+pub const TICKS_PER_SECOND: u32 = 60;
+pub const TICK: Duration = Duration::from_nanos(1_000_000_000 / TICKS_PER_SECOND as u64);
+
+// after a long hitch, give up on catching up past this
+// instead of freezing to run hundreds of ticks
+const MAX_TICKS_PER_FRAME: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub enum GameStage {
+    // runs the tick schedule below 0 or more times
+    Tick,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub enum TickStage {
+    Start,
+    Update,
+    End,
+}
+
+// the Time for gameplay. always moves forward by exactly TICK
+#[derive(Default)]
+pub struct GameTime {
+    tick: u64,
+    // the ticks that were actually played, not paused or on the game over screen
+    played: u64,
+    // real time that hasn't been used up by a tick yet
+    accumulator: Duration,
+}
+
+impl GameTime {
+    // how many ticks this run has had. the tick the run starts on is 0
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn delta(&self) -> Duration {
+        TICK
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        TICK.as_secs_f32()
+    }
+
+    pub fn played(&self) -> u64 {
+        self.played
+    }
+
+    // played since the run started. like Time::seconds_since_startup,
+    // but time spent in the menu or paused doesn't change how the run plays out
+    pub fn seconds(&self) -> f64 {
+        self.played as f64 * TICK.as_secs_f64()
+    }
+
+    // carry on from a snapshot
+    pub fn restore(&mut self, tick: u64, played: u64) {
+        self.tick = tick;
+        self.played = played;
+    }
+}
+
+// assets the fight needs before the first tick.
+// otherwise how quick the disk is changes which tick they show up on
+#[derive(Default)]
+pub struct TickAssets {
+    handles: Vec<HandleUntyped>,
+    loaded: bool,
+}
+
+impl TickAssets {
+    pub fn add<T: Asset>(&mut self, handle: &Handle<T>) {
+        self.handles.push(handle.clone_untyped());
+        self.loaded = false;
+    }
+}
+
+// the same idea as bevy's FixedTimestep, but counting in whole nanoseconds
+// so a headless frame of exactly TICK is always exactly one tick
+fn tick_due(
+    time: Res<Time>,
+    mut game_time: ResMut<GameTime>,
+    mut assets: ResMut<TickAssets>,
+    asset_server: Res<AssetServer>,
+    mut ticks_this_frame: Local<Option<u32>>,
+) -> ShouldRun {
+    if !assets.loaded {
+        let handles = assets.handles.iter().map(|handle| handle.id);
+        match asset_server.get_group_load_state(handles) {
+            // failed ones fall back to defaults, that's fine
+            LoadState::Loaded | LoadState::Failed => assets.loaded = true,
+            _ => return ShouldRun::No,
+        }
+    }
+
+    let ticks = match *ticks_this_frame {
+        Some(ticks) => ticks,
+        // first check this frame
+        None => {
+            game_time.accumulator += time.delta();
+            0
+        }
+    };
+
+    if ticks >= MAX_TICKS_PER_FRAME {
+        game_time.accumulator = Duration::ZERO;
+    }
+    if game_time.accumulator < TICK {
+        *ticks_this_frame = None;
+        return ShouldRun::No;
+    }
+
+    game_time.accumulator -= TICK;
+    game_time.tick += 1;
+    *ticks_this_frame = Some(ticks + 1);
+    ShouldRun::YesAndCheckAgain
+}
+
+// the seed for the next run. None picks a new one every run,
+// Some replays the same enemies, crits and spreads every time
+#[derive(Default)]
+pub struct RunSeed(pub Option<u64>);
+
+// the only randomness gameplay should use.
+// reseeded at the start of every run and the seed is printed,
+// so put it in bug reports
+//
+// the same generator as StdRng, but this one can say how far along it is
+// so a snapshot can put it back exactly
+pub struct GameRng {
+    seed: u64,
+    rng: ChaCha12Rng,
+}
+
+impl Default for GameRng {
+    fn default() -> Self {
+        GameRng::new(0)
+    }
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        GameRng {
+            seed,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+        }
+    }
+
+    // the seed, then skip ahead to where a snapshot left off
+    pub fn restore(seed: u64, words: u64) -> Self {
+        let mut rng = GameRng::new(seed);
+        rng.rng.set_word_pos(words as u128);
+        rng
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // what the next roll would be, without rolling it
+    pub fn peek(&self) -> u64 {
+        self.rng.clone().next_u64()
+    }
+
+    // how many 32 bit words have been rolled since the seed
+    pub fn words(&self) -> u64 {
+        self.rng.get_word_pos() as u64
+    }
+}
+
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+// on_enter(Playing). anything there that rolls dice or reads the seed goes .after(start_run)
+pub fn start_run(mut rng: ResMut<GameRng>, mut game_time: ResMut<GameTime>, seed: Res<RunSeed>) {
+    let seed = seed.0.unwrap_or_else(rand::random);
+    *rng = GameRng::new(seed);
+    game_time.tick = 0;
+    game_time.played = 0;
+    println!("Run seed: {}", seed);
+}
+
+// paused and game over ticks still run, for the menus and so replays line up,
+// but the fight's clock stays where it was
+fn count_played(mut game_time: ResMut<GameTime>, state: Res<State<AppState>>) {
+    if *state.current() == AppState::Playing {
+        game_time.played += 1;
+    }
+}
+
+// add_system_set, add_event etc but for the tick schedule
+pub trait TickApp {
+    fn add_tick_system<Params>(&mut self, system: impl IntoSystemDescriptor<Params>) -> &mut Self;
+    fn add_tick_system_set(&mut self, system_set: SystemSet) -> &mut Self;
+    fn add_tick_system_to_stage<Params>(
+        &mut self,
+        stage: TickStage,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut Self;
+    fn add_tick_event<T: Event>(&mut self) -> &mut Self;
+    // add_state, driven by the tick so on_enter etc can go in it
+    fn add_tick_state<T: StateData>(&mut self, initial: T) -> &mut Self;
+}
+
+impl TickApp for App {
+    fn add_tick_system<Params>(&mut self, system: impl IntoSystemDescriptor<Params>) -> &mut Self {
+        self.add_tick_system_to_stage(TickStage::Update, system)
+    }
+
+    fn add_tick_system_set(&mut self, system_set: SystemSet) -> &mut Self {
+        self.schedule.stage(GameStage::Tick, |tick: &mut Schedule| {
+            tick.add_system_set_to_stage(TickStage::Update, system_set)
+        });
+        self
+    }
+
+    fn add_tick_system_to_stage<Params>(
+        &mut self,
+        stage: TickStage,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut Self {
+        self.schedule.stage(GameStage::Tick, |tick: &mut Schedule| {
+            tick.add_system_to_stage(stage, system)
+        });
+        self
+    }
+
+    fn add_tick_event<T: Event>(&mut self) -> &mut Self {
+        if !self.world.contains_resource::<Events<T>>() {
+            self.init_resource::<Events<T>>()
+                .add_tick_system_to_stage(TickStage::Start, Events::<T>::update_system);
+        }
+        self
+    }
+
+    fn add_tick_state<T: StateData>(&mut self, initial: T) -> &mut Self {
+        self.insert_resource(State::new(initial));
+        self.schedule.stage(GameStage::Tick, |tick: &mut Schedule| {
+            tick.add_system_set_to_stage(TickStage::Update, State::<T>::get_driver())
+        });
+        self
+    }
+}
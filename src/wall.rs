@@ -0,0 +1,170 @@
+use bevy::{ecs::system::EntityCommands, prelude::*};
+use bevy_rapier2d::prelude::*;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    health::{self, DeathEvent},
+    state::AppState,
+    tick::{GameRng, GameTime, TickApp},
+    wave::{WaveEndEvent, WaveStartEvent},
+};
+
+// walls that do more than sit there. what bullets do to them
+// is in the wall branch of shooting::bullet_collision_rapier,
+// this is everything else: breaking apart and doors
+pub struct WallPlugin;
+
+impl Plugin for WallPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_tick_system_set(
+            SystemSet::on_update(AppState::Playing)
+                .with_system(break_walls.after(health::death))
+                .with_system(fly_debris)
+                .with_system(open_doors),
+        );
+    }
+}
+
+// has Health too. bullets and explosions damage it
+// and it breaks into Debris when it runs out
+#[derive(Component)]
+pub struct Breakable;
+
+// every bullet bounces off, not just ricochet ones
+#[derive(Component)]
+pub struct Reflective;
+
+// bullets going the way the wall's top faces (its local +y) go through,
+// bullets coming the other way hit it like a normal wall.
+// it still blocks movement both ways
+#[derive(Component)]
+pub struct OneWay;
+
+// a wall that gets out of the way when a wave is cleared
+#[derive(Component, Serialize, Deserialize, Clone, Copy)]
+pub struct Door {
+    // opens when this wave or any after it is cleared
+    pub after_wave: u32,
+    // otherwise it shuts again when the next wave starts
+    pub stay_open: bool,
+    pub open: bool,
+}
+
+impl Door {
+    // an open door is a sensor, so things walk through it.
+    // bullets check Door::open themselves
+    pub fn set_open(&mut self, open: bool, entity: &mut EntityCommands, sprite: &mut Sprite) {
+        self.open = open;
+        if open {
+            sprite.color.set_a(0.25);
+            entity.insert(Sensor);
+        } else {
+            sprite.color.set_a(1.);
+            entity.remove::<Sensor>();
+        }
+    }
+}
+
+impl OneWay {
+    // the way bullets are let through
+    pub fn direction(wall_trans: &Transform) -> Vec2 {
+        (wall_trans.rotation * Vec3::Y).truncate()
+    }
+}
+
+#[derive(Component)]
+pub struct Debris {
+    velocity: Vec2,
+    timer: Timer,
+}
+
+const DEBRIS_PIECES: u32 = 6;
+
+fn break_walls(
+    mut commands: Commands,
+    mut ev_death: EventReader<DeathEvent>,
+    q_breakable: Query<(&Sprite, &Transform), With<Breakable>>,
+    mut rng: ResMut<GameRng>,
+) {
+    for ev in ev_death.iter() {
+        let (sprite, transform) = match q_breakable.get(ev.entity) {
+            Ok(wall) => wall,
+            Err(_) => continue,
+        };
+        let size = sprite.custom_size.unwrap_or(Vec2::splat(50.));
+
+        for _ in 0..DEBRIS_PIECES {
+            // somewhere on the wall, flying away from the middle of it
+            let offset = Vec2::new(
+                rng.gen_range(-0.5..0.5) * size.x,
+                rng.gen_range(-0.5..0.5) * size.y,
+            );
+            let offset = (transform.rotation * offset.extend(0.)).truncate();
+            let velocity = offset.normalize_or_zero() * rng.gen_range(100.0..250.0);
+            let piece = size.min_element().clamp(6., 20.) * rng.gen_range(0.4..0.8);
+
+            commands
+                .spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        color: sprite.color,
+                        custom_size: Some(Vec2::splat(piece)),
+                        ..default()
+                    },
+                    transform: Transform {
+                        translation: (ev.position + offset).extend(0.),
+                        rotation: Quat::from_rotation_z(rng.gen_range(0.0..std::f32::consts::TAU)),
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(Debris {
+                    velocity,
+                    timer: Timer::from_seconds(rng.gen_range(0.4..0.8), false),
+                });
+        }
+    }
+}
+
+// slide to a stop and fade out
+fn fly_debris(
+    mut commands: Commands,
+    mut q_debris: Query<(Entity, &mut Debris, &mut Transform, &mut Sprite)>,
+    time: Res<GameTime>,
+) {
+    for (entity, mut debris, mut transform, mut sprite) in q_debris.iter_mut() {
+        if debris.timer.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let left = debris.timer.percent_left();
+        transform.translation += (debris.velocity * left * time.delta_seconds()).extend(0.);
+        sprite.color.set_a(left);
+    }
+}
+
+fn open_doors(
+    mut commands: Commands,
+    mut ev_start: EventReader<WaveStartEvent>,
+    mut ev_end: EventReader<WaveEndEvent>,
+    mut q_doors: Query<(Entity, &mut Door, &mut Sprite)>,
+) {
+    for ev in ev_end.iter() {
+        for (entity, mut door, mut sprite) in q_doors.iter_mut() {
+            if door.open || ev.wave < door.after_wave {
+                continue;
+            }
+            door.set_open(true, &mut commands.entity(entity), &mut sprite);
+        }
+    }
+
+    for _ in ev_start.iter() {
+        for (entity, mut door, mut sprite) in q_doors.iter_mut() {
+            if !door.open || door.stay_open {
+                continue;
+            }
+            door.set_open(false, &mut commands.entity(entity), &mut sprite);
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    enemy::{self, Enemy, EnemyKind, EnemySpawnEvent},
+    health::Dying,
+    level::{CurrentLevel, Level},
+    state::AppState,
+    tick::{GameRng, GameTime, TickApp, TickAssets},
+};
+
+pub struct WavePlugin;
+
+impl Plugin for WavePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<WaveTable>()
+            .init_asset_loader::<WaveTableLoader>()
+            .add_tick_event::<WaveStartEvent>()
+            .add_tick_event::<WaveEndEvent>()
+            .add_startup_system(preload_waves)
+            .add_tick_system_set(SystemSet::on_enter(AppState::Playing).with_system(setup_waves))
+            .add_tick_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    // spawn events get turned into enemies the same frame
+                    // so they're alive before the director checks if the wave is over
+                    .with_system(run_waves.before(enemy::how_to_spawn_enemies))
+                    .with_system(wave_event),
+            );
+    }
+}
+
+const WAVE_FILE: &str = "waves/default.waves.ron";
+
+// a 0 spawn_delay means the whole wave at once,
+// but a repeating Timer can't be 0 long
+const MIN_SPAWN_DELAY: f32 = 0.001;
+
+// all the waves for a fight, loaded from a .waves.ron file
+// after the last wave, it keeps repeating the last one but harder
+#[derive(Deserialize, TypeUuid, Debug, Clone)]
+#[uuid = "0b6f5f0e-7a43-4f0a-9d0c-3a2a6c9d1e57"]
+#[serde(default)]
+pub struct WaveTable {
+    // seconds between the end of one wave and the start of the next
+    pub intermission: f32,
+    pub waves: Vec<WaveDef>,
+    pub escalation: Escalation,
+}
+
+impl Default for WaveTable {
+    fn default() -> Self {
+        WaveTable {
+            intermission: 3.,
+            waves: vec![WaveDef::default()],
+            escalation: Escalation::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WaveDef {
+    pub enemies: Vec<EnemyGroup>,
+    // seconds between each enemy spawning
+    pub spawn_delay: f32,
+    // enemies spawn at these in order
+    // if there are none, they use the level's spawn points,
+    // and if it has none either, a ring around the middle
+    pub spawn_points: Vec<Vec2>,
+}
+
+impl Default for WaveDef {
+    fn default() -> Self {
+        WaveDef {
+            enemies: vec![EnemyGroup {
+                kind: EnemyKind::Walker,
+                count: 5,
+            }],
+            spawn_delay: 0.5,
+            spawn_points: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EnemyGroup {
+    pub kind: EnemyKind,
+    pub count: u32,
+}
+
+// how much harder each wave past the end of the list gets
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Escalation {
+    // added to every group's count
+    pub extra_enemies: u32,
+    // spawn_delay is multiplied by this
+    pub spawn_delay_scale: f32,
+    pub min_spawn_delay: f32,
+}
+
+impl Default for Escalation {
+    fn default() -> Self {
+        Escalation {
+            extra_enemies: 2,
+            spawn_delay_scale: 0.9,
+            min_spawn_delay: 0.1,
+        }
+    }
+}
+
+impl WaveTable {
+    // anything in the file that would panic a Timer
+    fn sanitized(mut self) -> Self {
+        self.intermission = self.intermission.max(0.);
+        for wave in self.waves.iter_mut() {
+            wave.spawn_delay = wave.spawn_delay.max(MIN_SPAWN_DELAY);
+        }
+        self.escalation.min_spawn_delay = self.escalation.min_spawn_delay.max(MIN_SPAWN_DELAY);
+        self
+    }
+
+    // wave numbers start at 1
+    pub fn wave(&self, number: u32) -> WaveDef {
+        let last = match self.waves.last() {
+            Some(last) => last,
+            None => return WaveDef::default(),
+        };
+
+        let index = (number.max(1) - 1) as usize;
+        if let Some(wave) = self.waves.get(index) {
+            return wave.clone();
+        }
+
+        // past the end of the list
+        let extra = (index + 1 - self.waves.len()) as u32;
+        let mut wave = last.clone();
+        for group in wave.enemies.iter_mut() {
+            group.count += self.escalation.extra_enemies * extra;
+        }
+        wave.spawn_delay = (wave.spawn_delay
+            * self.escalation.spawn_delay_scale.powi(extra as i32))
+        .max(self.escalation.min_spawn_delay);
+        wave
+    }
+}
+
+#[derive(Default)]
+struct WaveTableLoader;
+
+impl AssetLoader for WaveTableLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let table = ron::de::from_bytes::<WaveTable>(bytes)?.sanitized();
+            load_context.set_default_asset(LoadedAsset::new(table));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["waves.ron"]
+    }
+}
+
+pub struct WaveStartEvent {
+    pub wave: u32,
+}
+
+pub struct WaveEndEvent {
+    pub wave: u32,
+}
+
+// decides when and where enemies spawn
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WaveDirector {
+    // the current wave, 0 before the first one starts
+    pub wave: u32,
+    state: WaveState,
+    #[serde(skip)]
+    table: Handle<WaveTable>,
+}
+
+impl WaveDirector {
+    // carry on from a snapshot with the wave file that's loaded now
+    pub fn restore(&mut self, saved: WaveDirector) {
+        self.wave = saved.wave;
+        self.state = saved.state;
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+enum WaveState {
+    Intermission(#[serde(with = "crate::snapshot::timer")] Timer),
+    Spawning {
+        queue: Vec<EnemyKind>,
+        spawn_points: Vec<Vec2>,
+        spawned: usize,
+        #[serde(with = "crate::snapshot::timer")]
+        timer: Timer,
+    },
+    // everything has spawned, waiting for them to die
+    Fighting,
+}
+
+fn preload_waves(asset_server: Res<AssetServer>, mut tick_assets: ResMut<TickAssets>) {
+    tick_assets.add(&asset_server.load::<WaveTable, _>(WAVE_FILE));
+}
+
+fn setup_waves(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(WaveDirector {
+        wave: 0,
+        // short breather before the first wave
+        state: WaveState::Intermission(Timer::from_seconds(1.0, false)),
+        table: asset_server.load(WAVE_FILE),
+    });
+}
+
+fn run_waves(
+    mut director: ResMut<WaveDirector>,
+    tables: Res<Assets<WaveTable>>,
+    // dying ones don't count, they're already dead
+    q_enemies: Query<(), (With<Enemy>, Without<Dying>)>,
+    mut ev_spawn: EventWriter<EnemySpawnEvent>,
+    mut ev_start: EventWriter<WaveStartEvent>,
+    mut ev_end: EventWriter<WaveEndEvent>,
+    current_level: Res<CurrentLevel>,
+    levels: Res<Assets<Level>>,
+    time: Res<GameTime>,
+    mut rng: ResMut<GameRng>,
+    fallback: Local<WaveTable>,
+) {
+    // use the default waves until the file loads
+    let table = tables.get(&director.table).unwrap_or(&fallback);
+    let director = &mut *director;
+
+    match &mut director.state {
+        WaveState::Intermission(timer) => {
+            if timer.tick(time.delta()).just_finished() {
+                director.wave += 1;
+                let wave = table.wave(director.wave);
+                let spawn_points = match current_level.get(&levels) {
+                    Some(level) if wave.spawn_points.is_empty() => level.enemy_spawns.clone(),
+                    _ => wave.spawn_points.clone(),
+                };
+
+                director.state = WaveState::Spawning {
+                    queue: spawn_queue(&wave),
+                    spawn_points,
+                    spawned: 0,
+                    // first enemy comes out right away
+                    timer: Timer::from_seconds(wave.spawn_delay, true),
+                };
+                ev_start.send(WaveStartEvent {
+                    wave: director.wave,
+                });
+                ev_spawn_next(&mut director.state, &mut ev_spawn, &mut rng);
+            }
+        }
+        WaveState::Spawning { timer, .. } => {
+            let ticks = timer.tick(time.delta()).times_finished_this_tick();
+            for _ in 0..ticks {
+                ev_spawn_next(&mut director.state, &mut ev_spawn, &mut rng);
+            }
+        }
+        WaveState::Fighting => {
+            if q_enemies.is_empty() {
+                ev_end.send(WaveEndEvent {
+                    wave: director.wave,
+                });
+                director.state =
+                    WaveState::Intermission(Timer::from_seconds(table.intermission, false));
+            }
+        }
+    }
+}
+
+// send the next enemy in the queue
+// and switch to fighting once they're all out
+fn ev_spawn_next(
+    state: &mut WaveState,
+    ev_spawn: &mut EventWriter<EnemySpawnEvent>,
+    rng: &mut GameRng,
+) {
+    if let WaveState::Spawning {
+        queue,
+        spawn_points,
+        spawned,
+        ..
+    } = state
+    {
+        if let Some(kind) = queue.pop() {
+            let position = if spawn_points.is_empty() {
+                random_spawn_point(rng)
+            } else {
+                spawn_points[*spawned % spawn_points.len()]
+            };
+            *spawned += 1;
+            ev_spawn.send(EnemySpawnEvent { kind, position });
+        }
+
+        if queue.is_empty() {
+            *state = WaveState::Fighting;
+        }
+    }
+}
+
+// mix the groups together so they don't all come out in one clump
+fn spawn_queue(wave: &WaveDef) -> Vec<EnemyKind> {
+    let mut queue = Vec::new();
+    let most = wave
+        .enemies
+        .iter()
+        .map(|group| group.count)
+        .max()
+        .unwrap_or(0);
+
+    for i in 0..most {
+        for group in wave.enemies.iter() {
+            if i < group.count {
+                queue.push(group.kind);
+            }
+        }
+    }
+
+    // popped from the back
+    queue.reverse();
+    queue
+}
+
+fn random_spawn_point(rng: &mut GameRng) -> Vec2 {
+    Vec2::new(rng.gen_range(-1.0..=1.0), rng.gen_range(-1.0..=1.0)).normalize_or_zero() * 200.
+}
+
+fn wave_event(mut ev_start: EventReader<WaveStartEvent>, mut ev_end: EventReader<WaveEndEvent>) {
+    for ev in ev_start.iter() {
+        println!("Wave {:?} started", ev.wave);
+    }
+    for ev in ev_end.iter() {
+        println!("Wave {:?} cleared", ev.wave);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        headless::HeadlessApp,
+        input::InputScript,
+        tick::{self, TICKS_PER_SECOND},
+    };
+
+    fn wave(sim: &mut HeadlessApp) -> u32 {
+        sim.world().resource::<WaveDirector>().wave
+    }
+
+    #[test]
+    fn clearing_a_wave_starts_the_next() {
+        // standing still, so nothing gets killed by accident
+        let mut sim = HeadlessApp::new(InputScript::default());
+
+        // the first breather and all five walkers
+        sim.run_ticks(4 * TICKS_PER_SECOND as u64);
+        assert_eq!(wave(&mut sim), 1);
+
+        let world = sim.world();
+        let enemies: Vec<Entity> = world
+            .query_filtered::<Entity, With<Enemy>>()
+            .iter(world)
+            .collect();
+        assert!(!enemies.is_empty(), "wave 1 didn't spawn anything");
+        for enemy in enemies {
+            world.despawn(enemy);
+        }
+
+        // the intermission is 3 seconds
+        sim.run_ticks(TICKS_PER_SECOND as u64);
+        assert_eq!(wave(&mut sim), 1);
+        sim.run_ticks(3 * TICKS_PER_SECOND as u64);
+        assert_eq!(wave(&mut sim), 2);
+    }
+
+    #[test]
+    fn waves_past_the_list_get_harder() {
+        let table = WaveTable::default();
+        assert_eq!(table.wave(1).enemies[0].count, 5);
+
+        let third = table.wave(3);
+        assert_eq!(third.enemies[0].count, 9);
+        assert!((third.spawn_delay - 0.5 * 0.9 * 0.9).abs() < 1e-5);
+    }
+
+    #[test]
+    fn zero_spawn_delay_spawns_the_wave_at_once() {
+        let table: WaveTable = ron::from_str(
+            "(waves: [(spawn_delay: 0.0)], escalation: (min_spawn_delay: -1.0), intermission: -3.0)",
+        )
+        .unwrap();
+        let table = table.sanitized();
+
+        // past the end of the list too, where min_spawn_delay kicks in
+        for number in 1..4 {
+            let wave = table.wave(number);
+            let mut timer = Timer::from_seconds(wave.spawn_delay, true);
+            let spawned = timer.tick(tick::TICK).times_finished_this_tick();
+            assert!(
+                spawned >= spawn_queue(&wave).len() as u32,
+                "wave {}",
+                number
+            );
+        }
+        Timer::from_seconds(table.intermission, false).tick(tick::TICK);
+    }
+
+    #[test]
+    fn spawn_queue_mixes_the_groups() {
+        let wave = WaveDef {
+            enemies: vec![
+                EnemyGroup {
+                    kind: EnemyKind::Walker,
+                    count: 2,
+                },
+                EnemyGroup {
+                    kind: EnemyKind::Charger,
+                    count: 1,
+                },
+            ],
+            ..default()
+        };
+
+        let mut queue = spawn_queue(&wave);
+        let mut order = Vec::new();
+        while let Some(kind) = queue.pop() {
+            order.push(kind);
+        }
+        assert_eq!(
+            order,
+            vec![EnemyKind::Walker, EnemyKind::Charger, EnemyKind::Walker]
+        );
+    }
+}
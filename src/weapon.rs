@@ -0,0 +1,351 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::PlayerInputs,
+    shooting::{Gun, Shotgun, ShotgunGauge},
+    snapshot::Restored,
+    state::AppState,
+    tick::{TickApp, TickAssets},
+    Player,
+};
+
+pub struct WeaponPlugin;
+
+impl Plugin for WeaponPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<WeaponDef>()
+            .init_asset_loader::<WeaponDefLoader>()
+            .add_startup_system(load_weapons)
+            .add_tick_system_set(SystemSet::on_update(AppState::Playing).with_system(switch_weapon))
+            // keeps working in the menus so you can tune a weapon while paused
+            .add_tick_system(apply_weapon_defs.after(switch_weapon));
+    }
+}
+
+// the weapons you can switch between with 1, 2, 3...
+// files live in assets/weapons
+const WEAPON_FILES: [&str; 4] = [
+    "weapons/pistol.weapon.ron",
+    "weapons/shotgun.weapon.ron",
+    "weapons/rifle.weapon.ron",
+    "weapons/scattergun.weapon.ron",
+];
+
+pub const STARTING_WEAPON: &str = "weapons/shotgun.weapon.ron";
+
+// everything about a gun that a designer might want to tune
+// loaded from a .weapon.ron file
+// any field left out of the file uses the default (the old hardcoded shotgun)
+#[derive(Deserialize, TypeUuid, Debug, Clone)]
+#[uuid = "6f1c2b7e-3d2a-4c55-9a51-52c3b8e0f6a1"]
+#[serde(default)]
+pub struct WeaponDef {
+    pub name: String,
+    pub clip_size: u32,
+    pub time_between_shots: f32,
+    // seconds. for ShellByShell this is per shell
+    pub reload_time: f32,
+    pub reload_style: ReloadStyle,
+    pub damage: u32,
+    // seconds
+    pub bullet_lifetime: f32,
+    // pixels per second
+    pub projectile_speed: f32,
+    // more than 1 makes it a shotgun
+    pub pellets: u32,
+    // degrees from the aim direction to the outermost pellet
+    pub spread: f32,
+    pub spread_pattern: SpreadPattern,
+    // how many pellets need to hit for a free reload
+    pub perfect_shot: PerfectShotRule,
+}
+
+impl Default for WeaponDef {
+    fn default() -> Self {
+        WeaponDef {
+            name: "Shotgun".to_string(),
+            clip_size: 2,
+            time_between_shots: 0.3,
+            reload_time: 2.0,
+            reload_style: ReloadStyle::FullClip,
+            damage: 1,
+            bullet_lifetime: 1.0,
+            projectile_speed: 700.,
+            pellets: 2,
+            spread: 6.,
+            spread_pattern: SpreadPattern::Fan,
+            perfect_shot: PerfectShotRule::AllHit,
+        }
+    }
+}
+
+// a 0 reload is instant, but the reload Timer repeats and can't be 0 long
+const MIN_RELOAD_TIME: f32 = 0.001;
+
+impl WeaponDef {
+    // anything in the file that would panic a Timer or leave a gun with no clip.
+    // one typo while tuning shouldn't take the game down
+    fn sanitized(mut self) -> Self {
+        self.clip_size = self.clip_size.max(1);
+        self.time_between_shots = self.time_between_shots.max(0.);
+        self.reload_time = self.reload_time.max(MIN_RELOAD_TIME);
+        self.bullet_lifetime = self.bullet_lifetime.max(0.);
+        self
+    }
+}
+
+// in the file: FullClip or ShellByShell
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReloadStyle {
+    // nothing until the whole clip goes in at once
+    FullClip,
+    // one shell at a time. you can shoot again with what's loaded so far,
+    // which stops the reload
+    ShellByShell,
+}
+
+// how the pellets of a shot are spread out
+// in the file: Fan, RandomCone or Seeded(1234)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SpreadPattern {
+    // evenly spaced from -spread to spread
+    Fan,
+    // random angles inside the spread, different every shot
+    RandomCone,
+    // random angles inside the spread, but the same every shot
+    Seeded(u64),
+}
+
+impl SpreadPattern {
+    // angle of each pellet from the aim direction, in radians
+    pub fn angles(&self, pellets: u32, spread: f32, rng: &mut impl Rng) -> Vec<f32> {
+        // a negative spread in a weapon file would make gen_range panic
+        let spread = spread.abs().to_radians();
+
+        match self {
+            SpreadPattern::Fan => {
+                if pellets <= 1 {
+                    return vec![0.; pellets as usize];
+                }
+                // 2 pellets is the old left/right shotgun
+                let gap = 2. * spread / (pellets - 1) as f32;
+                (0..pellets).map(|i| spread - gap * i as f32).collect()
+            }
+            SpreadPattern::RandomCone => (0..pellets)
+                .map(|_| rng.gen_range(-spread..=spread))
+                .collect(),
+            SpreadPattern::Seeded(seed) => {
+                let mut rng = StdRng::seed_from_u64(*seed);
+                (0..pellets)
+                    .map(|_| rng.gen_range(-spread..=spread))
+                    .collect()
+            }
+        }
+    }
+}
+
+// what counts as a perfect shot
+// in the file: AllHit, AtLeast(3) or Fraction(0.75)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PerfectShotRule {
+    AllHit,
+    AtLeast(u32),
+    Fraction(f32),
+}
+
+impl PerfectShotRule {
+    pub fn is_perfect(&self, hits: u32, pellets: u32) -> bool {
+        if pellets == 0 {
+            return false;
+        }
+
+        match self {
+            PerfectShotRule::AllHit => hits == pellets,
+            PerfectShotRule::AtLeast(n) => hits >= *n,
+            PerfectShotRule::Fraction(fraction) => hits as f32 >= *fraction * pellets as f32,
+        }
+    }
+}
+
+#[derive(Default)]
+struct WeaponDefLoader;
+
+impl AssetLoader for WeaponDefLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let def = ron::de::from_bytes::<WeaponDef>(bytes)?.sanitized();
+            load_context.set_default_asset(LoadedAsset::new(def));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["weapon.ron"]
+    }
+}
+
+// which weapon file this gun is built from
+// the Gun is rebuilt when the handle changes
+// and retuned when the file changes on disk
+#[derive(Component)]
+pub struct Weapon(pub Handle<WeaponDef>);
+
+pub struct WeaponLibrary {
+    weapons: Vec<Handle<WeaponDef>>,
+}
+
+fn load_weapons(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut tick_assets: ResMut<TickAssets>,
+) {
+    let weapons: Vec<Handle<WeaponDef>> = WEAPON_FILES
+        .iter()
+        .map(|path| asset_server.load(*path))
+        .collect();
+    for weapon in weapons.iter() {
+        tick_assets.add(weapon);
+    }
+
+    commands.insert_resource(WeaponLibrary { weapons });
+}
+
+fn switch_weapon(
+    player_inputs: Res<PlayerInputs>,
+    library: Option<Res<WeaponLibrary>>,
+    mut q_player: Query<(&Player, &mut Weapon)>,
+) {
+    let library = match library {
+        Some(library) => library,
+        None => return,
+    };
+
+    for (player, mut weapon) in q_player.iter_mut() {
+        let slot = match player_inputs.get(player).switch_weapon {
+            Some(slot) => slot,
+            None => continue,
+        };
+        if let Some(handle) = library.weapons.get(slot) {
+            if weapon.0 != *handle {
+                weapon.0 = handle.clone();
+            }
+        }
+    }
+}
+
+fn apply_weapon_defs(
+    mut commands: Commands,
+    mut ev_asset: EventReader<AssetEvent<WeaponDef>>,
+    defs: Res<Assets<WeaponDef>>,
+    mut q_guns: Query<(
+        Entity,
+        ChangeTrackers<Weapon>,
+        &Weapon,
+        &mut Gun,
+        Option<&mut ShotgunGauge>,
+        Option<&Restored>,
+    )>,
+) {
+    let mut modified = Vec::new();
+    for ev in ev_asset.iter() {
+        match ev {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                modified.push(handle.clone());
+            }
+            AssetEvent::Removed { .. } => (),
+        }
+    }
+
+    for (entity, tracker, weapon, mut gun, gauge, restored) in q_guns.iter_mut() {
+        let def = match defs.get(&weapon.0) {
+            Some(def) => def,
+            None => continue,
+        };
+
+        if restored.is_some() {
+            // loaded from a snapshot, the gun is already how it was
+            commands.entity(entity).remove::<Restored>();
+            continue;
+        }
+
+        if tracker.is_changed() {
+            // switched weapons, start with a full clip
+            *gun = Gun::from_def(def);
+        } else if modified.contains(&weapon.0) {
+            // file changed on disk, keep the ammo you have
+            gun.apply_def(def);
+            println!("Loaded weapon {:?}", def.name);
+        } else {
+            continue;
+        }
+
+        if def.pellets > 1 {
+            commands.entity(entity).insert(Shotgun);
+            match gauge {
+                Some(mut gauge) => gauge.fit_clip(def.clip_size as usize),
+                None => {
+                    commands
+                        .entity(entity)
+                        .insert(ShotgunGauge::new(def.clip_size as usize));
+                }
+            }
+        } else {
+            commands.entity(entity).remove::<Shotgun>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick;
+
+    #[test]
+    fn negative_spread_is_the_same_as_positive() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for pattern in [
+            SpreadPattern::Fan,
+            SpreadPattern::RandomCone,
+            SpreadPattern::Seeded(7),
+        ] {
+            let angles = pattern.angles(5, -10., &mut rng);
+            assert_eq!(angles.len(), 5);
+            let limit = 10f32.to_radians() + f32::EPSILON;
+            assert!(
+                angles.iter().all(|angle| angle.abs() <= limit),
+                "{:?}",
+                angles
+            );
+        }
+    }
+
+    #[test]
+    fn zero_and_negative_times_dont_panic() {
+        let def: WeaponDef = ron::from_str(
+            "(clip_size: 0, time_between_shots: -1.0, reload_time: 0.0, bullet_lifetime: -2.0)",
+        )
+        .unwrap();
+        let def = def.sanitized();
+        assert_eq!(def.clip_size, 1);
+        assert_eq!(def.time_between_shots, 0.);
+
+        let mut gun = Gun::from_def(&def);
+        assert!(gun.reload_timer.tick(tick::TICK).just_finished());
+
+        // a hot reload to a negative time
+        let def: WeaponDef = ron::from_str("(reload_time: -1.0)").unwrap();
+        gun.apply_def(&def.sanitized());
+        gun.reload_timer.tick(tick::TICK);
+    }
+}